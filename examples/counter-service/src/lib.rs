@@ -1,4 +1,5 @@
 use stateroom_wasm::prelude::*;
+use std::convert::TryInto;
 
 #[stateroom_wasm]
 struct SharedCounterServer(i32);
@@ -17,4 +18,16 @@ impl SimpleStateroomService for SharedCounterServer {
 
         ctx.send_message(MessageRecipient::Broadcast, &format!("new value: {}", self.0));
     }
-}
\ No newline at end of file
+
+    fn snapshot(&self) -> Option<Vec<u8>> {
+        Some(self.0.to_le_bytes().to_vec())
+    }
+
+    fn restore(&mut self, snapshot: &[u8], _: &impl StateroomContext) -> Result<(), String> {
+        let count = snapshot
+            .try_into()
+            .map_err(|_| "Snapshot should be four bytes.".to_string())?;
+        self.0 = i32::from_le_bytes(count);
+        Ok(())
+    }
+}
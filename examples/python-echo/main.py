@@ -1,5 +1,6 @@
 #!/usr/bin/env python3
 
+import base64
 import json
 
 def main():
@@ -13,6 +14,9 @@ def main():
             response_message = f"Client {client} connected."
         elif message["type"] == "Disconnect":
             response_message = f"Client {client} disconnected."
+        elif message["type"] == "Message" and "Bytes" in message["message"]:
+            client_bytes = base64.b64decode(message["message"]["Bytes"])
+            response_message = f"Client {client} sent {len(client_bytes)} bytes."
         elif message["type"] == "Message":
             client_message = message["message"]["Text"]
             response_message = f"Client {client} sent `{client_message}`."
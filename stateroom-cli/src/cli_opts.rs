@@ -44,4 +44,20 @@ pub struct ServeCommand {
     /// assumed to be disconnected.
     #[clap(short = 't', long, default_value = "120")]
     pub heartbeat_timeout: u64,
+
+    /// Seed the random number generator of WebAssembly modules, so that
+    /// rooms produce the same random values each time the server runs.
+    #[clap(long)]
+    pub seed: Option<u64>,
+
+    /// Give each thread that runs WebAssembly modules its own engine, rather
+    /// than sharing one engine between all threads.
+    #[clap(long)]
+    pub engine_per_thread: bool,
+
+    /// Token that clients must present (as a bearer token) to use the
+    /// `/stats` and `/reload` admin endpoints. Without it, those endpoints
+    /// are not served.
+    #[clap(long)]
+    pub admin_token: Option<String>,
 }
@@ -3,7 +3,7 @@ use std::{ffi::OsStr, path::Path, time::Duration};
 use crate::cli_opts::ServeCommand;
 use stateroom_server::Server;
 use stateroom_stdio::StdioProcessServiceFactory;
-use stateroom_wasm_host::WasmHostFactory;
+use stateroom_wasm_host::{EngineMode, WasmHostConfig, WasmHostFactory};
 
 pub fn serve(serve_opts: ServeCommand) -> anyhow::Result<()> {
     let ServeCommand {
@@ -11,6 +11,9 @@ pub fn serve(serve_opts: ServeCommand) -> anyhow::Result<()> {
         port,
         heartbeat_interval,
         heartbeat_timeout,
+        seed,
+        engine_per_thread,
+        admin_token,
     } = serve_opts;
 
     let path = Path::new(&module);
@@ -23,11 +26,24 @@ pub fn serve(serve_opts: ServeCommand) -> anyhow::Result<()> {
         heartbeat_interval: Duration::from_secs(heartbeat_interval),
         heartbeat_timeout: Duration::from_secs(heartbeat_timeout),
         port,
+        admin_token,
         ..Server::default()
     };
 
+    let engine_mode = if engine_per_thread {
+        EngineMode::PerThread
+    } else {
+        EngineMode::Shared
+    };
+
+    let host_config = WasmHostConfig {
+        seed,
+        engine_mode,
+        ..WasmHostConfig::default()
+    };
+
     if let Some("wasm" | "wat") = ext.as_deref() {
-        let host_factory = WasmHostFactory::new(&module)?;
+        let host_factory = WasmHostFactory::new_with_config(&module, host_config)?;
         server_settings.serve(host_factory).map_err(|e| e.into())
     } else if path.is_file() {
         // Assume that module represents a system process.
@@ -48,7 +64,7 @@ pub fn serve(serve_opts: ServeCommand) -> anyhow::Result<()> {
             None
         };
 
-        let host_factory = WasmHostFactory::new(&server_module)?;
+        let host_factory = WasmHostFactory::new_with_config(&server_module, host_config)?;
 
         server_settings
             .with_static_path(static_dir)
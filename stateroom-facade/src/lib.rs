@@ -0,0 +1,79 @@
+//! Re-exports the commonly used types from the Stateroom crates behind feature
+//! flags, so that `use stateroom_facade::prelude::*` is enough for most
+//! applications. Each crate is also re-exported in full under a short name.
+
+pub use stateroom as core;
+#[cfg(feature = "server")]
+pub use stateroom_server as server;
+#[cfg(feature = "stdio")]
+pub use stateroom_stdio as stdio;
+#[cfg(feature = "wasm-host")]
+pub use stateroom_wasm_host as wasm_host;
+
+pub mod prelude {
+    pub use stateroom::{
+        ClientId, ConnectionMetadata, DisconnectReason, MessageRecipient, SimpleStateroomService,
+        StateroomContext, StateroomService, StateroomServiceFactory, WrappedStateroomService,
+    };
+
+    #[cfg(feature = "server")]
+    pub use stateroom_server::{Server, ServiceActorContext};
+
+    #[cfg(feature = "stdio")]
+    pub use stateroom_stdio::StdioProcessServiceFactory;
+
+    #[cfg(feature = "wasm-host")]
+    pub use stateroom_wasm_host::{EngineMode, WasmHostConfig, WasmHostFactory};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::prelude::*;
+
+    struct NoopService;
+
+    impl SimpleStateroomService for NoopService {
+        fn new(_: &str, _: &impl StateroomContext) -> Self {
+            NoopService
+        }
+    }
+
+    struct NoopContext;
+
+    impl StateroomContext for NoopContext {
+        fn send_message(&self, _: impl Into<MessageRecipient>, _: &str) {}
+
+        fn send_binary(&self, _: impl Into<MessageRecipient>, _: &[u8]) {}
+
+        fn set_timer(&self, _: u32) {}
+    }
+
+    fn assert_service<T: StateroomService>() {}
+
+    #[allow(unused)]
+    fn assert_factory<T: StateroomServiceFactory<C>, C: StateroomContext>() {}
+
+    /// Fails to compile if any of the expected names are missing from the prelude.
+    #[test]
+    fn test_prelude_exports() {
+        let _ = ClientId::from(1);
+        let _ = MessageRecipient::Broadcast;
+        let _ = DisconnectReason::default();
+        assert_service::<WrappedStateroomService<NoopService, NoopContext>>();
+
+        #[cfg(feature = "server")]
+        {
+            let _ = Server::default();
+            let _: Option<ServiceActorContext> = None;
+        }
+
+        #[cfg(feature = "stdio")]
+        assert_factory::<StdioProcessServiceFactory, NoopContext>();
+
+        #[cfg(feature = "wasm-host")]
+        {
+            let _ = WasmHostConfig::default();
+            assert_factory::<WasmHostFactory, NoopContext>();
+        }
+    }
+}
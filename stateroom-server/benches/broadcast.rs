@@ -0,0 +1,133 @@
+//! Measures the memory the room allocates to deliver a large broadcast to many
+//! clients, compared with sending each client its own copy of the payload.
+//!
+//! Run with `cargo bench -p stateroom-server`. The number of clients defaults
+//! to 1000, and can be set with the `CLIENTS` environment variable.
+
+use actix::{Actor, Addr, Context, Handler};
+use stateroom::{ClientId, ConnectionMetadata, MessageRecipient};
+use stateroom_server::{
+    AuthorizationResult, CloseConnection, MessageFromClient, MessageFromServer, RoomActor,
+};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+const PAYLOAD_SIZE: usize = 64 * 1024;
+
+/// Counts the bytes allocated by the process.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Stands in for both the service and the client connections, discarding
+/// everything it receives.
+struct Sink;
+
+impl Actor for Sink {
+    type Context = Context<Self>;
+}
+
+impl Handler<MessageFromClient> for Sink {
+    type Result = ();
+
+    fn handle(&mut self, _: MessageFromClient, _: &mut Self::Context) {}
+}
+
+impl Handler<MessageFromServer> for Sink {
+    type Result = ();
+
+    fn handle(&mut self, _: MessageFromServer, _: &mut Self::Context) {}
+}
+
+impl Handler<CloseConnection> for Sink {
+    type Result = ();
+
+    fn handle(&mut self, _: CloseConnection, _: &mut Self::Context) {}
+}
+
+/// Returns the number of bytes allocated to create the messages returned by
+/// `messages` and have the room deliver them.
+async fn allocated_by(
+    room: &Addr<RoomActor>,
+    messages: impl FnOnce() -> Vec<MessageFromServer>,
+) -> usize {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    for message in messages() {
+        room.send(message).await.unwrap();
+    }
+    ALLOCATED.load(Ordering::Relaxed) - before
+}
+
+fn main() {
+    let clients: u32 = std::env::var("CLIENTS")
+        .ok()
+        .and_then(|clients| clients.parse().ok())
+        .unwrap_or(1000);
+
+    actix::System::new().block_on(async move {
+        let sink = Sink.start();
+        let room = RoomActor::new(sink.clone().recipient(), Arc::default()).start();
+
+        for id in 1..=clients {
+            room.do_send(MessageFromClient::Connect(
+                ClientId::from(id),
+                sink.clone().recipient(),
+                sink.clone().recipient(),
+                ConnectionMetadata::default(),
+            ));
+            room.send(AuthorizationResult {
+                client: ClientId::from(id),
+                result: Ok(()),
+            })
+            .await
+            .unwrap();
+        }
+
+        let payload = vec![7; PAYLOAD_SIZE];
+        println!("{} clients, {} byte payload", clients, PAYLOAD_SIZE);
+
+        let shared = allocated_by(&room, || {
+            vec![MessageFromServer::new_binary(
+                MessageRecipient::Broadcast,
+                payload.clone(),
+            )]
+        })
+        .await;
+        println!(
+            "Broadcast: {} bytes allocated ({} per client)",
+            shared,
+            shared / clients as usize
+        );
+
+        let copied = allocated_by(&room, || {
+            (1..=clients)
+                .map(|id| MessageFromServer::new_binary(ClientId::from(id).into(), payload.clone()))
+                .collect()
+        })
+        .await;
+        println!(
+            "Per-client copies: {} bytes allocated ({} per client)",
+            copied,
+            copied / clients as usize
+        );
+    });
+}
@@ -1,8 +1,14 @@
-use crate::messages::{MessageData, MessageFromClient, MessageFromServer};
-use actix::{Actor, ActorContext, AsyncContext, Handler, Recipient, SpawnHandle, StreamHandler};
+use crate::messages::{CloseConnection, MessageData, MessageFromClient, MessageFromServer};
+use actix::{
+    Actor, ActorContext, AsyncContext, Handler, Message, Recipient, SpawnHandle, StreamHandler,
+};
+use actix_http::ws::Item;
 use actix_web_actors::ws;
-use stateroom::ClientId;
-use std::time::{Duration, Instant};
+use stateroom::{ClientId, DisconnectReason};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
 
 /// Represents a connection from a service to a client, which consists of a
 /// message receiver and a user ID.
@@ -13,6 +19,76 @@ pub struct ClientSocketConnection {
     pub heartbeat_interval: Duration,
     pub heartbeat_timeout: Duration,
     pub interval_handle: Option<SpawnHandle>,
+    /// Messages longer than this many bytes are sent as a series of frames of
+    /// at most this size, which the client reassembles into one message. If
+    /// `None`, every message is sent in a single frame.
+    pub outbound_chunk_size: Option<usize>,
+    /// Frames waiting to be written while a chunked message is sent. Frames
+    /// are written one per turn of the actor's event loop, and any messages
+    /// that arrive in the meantime are queued behind them.
+    pub(crate) outbound_frames: VecDeque<ws::Message>,
+}
+
+/// Writes the next frame in [ClientSocketConnection::outbound_frames].
+struct WriteNextFrame;
+
+impl Message for WriteNextFrame {
+    type Result = ();
+}
+
+/// The most bytes of description a close frame can carry: its payload is
+/// limited to 125 bytes, two of which hold the close code.
+const MAX_CLOSE_DESCRIPTION_LEN: usize = 123;
+
+/// Shortens `description` to fit in a close frame, cutting it at a character
+/// boundary.
+fn truncate_close_description(mut description: String) -> String {
+    if description.len() > MAX_CLOSE_DESCRIPTION_LEN {
+        let mut len = MAX_CLOSE_DESCRIPTION_LEN;
+        while !description.is_char_boundary(len) {
+            len -= 1;
+        }
+        description.truncate(len);
+    }
+
+    description
+}
+
+/// Splits a message into the frames used to send it: a single frame if it
+/// fits within `chunk_size` bytes, or otherwise a first frame, continuation
+/// frames, and a final frame, each at most `chunk_size` bytes long.
+fn into_frames(data: MessageData, chunk_size: Option<usize>) -> Vec<ws::Message> {
+    let chunk_size = match chunk_size {
+        Some(chunk_size) if data.len() > chunk_size => chunk_size.max(1),
+        _ => {
+            return vec![match data {
+                MessageData::String(st) => ws::Message::Text(st),
+                MessageData::Binary(bin) => ws::Message::Binary(bin),
+            }]
+        }
+    };
+
+    let (bytes, is_text) = match data {
+        MessageData::String(st) => (st.into_bytes(), true),
+        MessageData::Binary(bin) => (bin, false),
+    };
+
+    let count = (bytes.len() + chunk_size - 1) / chunk_size;
+    (0..count)
+        .map(|i| {
+            let chunk = bytes.slice(i * chunk_size..bytes.len().min((i + 1) * chunk_size));
+            let item = if i == 0 && is_text {
+                Item::FirstText(chunk)
+            } else if i == 0 {
+                Item::FirstBinary(chunk)
+            } else if i == count - 1 {
+                Item::Last(chunk)
+            } else {
+                Item::Continue(chunk)
+            };
+            ws::Message::Continuation(item)
+        })
+        .collect()
 }
 
 impl ClientSocketConnection {
@@ -23,18 +99,18 @@ impl ClientSocketConnection {
                     client_id=?act.client_id,
                     "Stopping ClientSocketConnection because heartbeat not responded.",
                 );
-                act.close(ctx);
+                act.close(ctx, DisconnectReason::HeartbeatTimeout);
             } else {
                 ctx.ping(b"");
             }
         }));
     }
 
-    fn close(&self, ctx: &mut ws::WebsocketContext<Self>) {
+    fn close(&self, ctx: &mut ws::WebsocketContext<Self>, reason: DisconnectReason) {
         self.interval_handle.map(|d| ctx.cancel_future(d));
 
         self.room
-            .do_send(MessageFromClient::Disconnect(self.client_id));
+            .do_send(MessageFromClient::Disconnect(self.client_id, reason));
 
         ctx.stop();
     }
@@ -52,10 +128,61 @@ impl Handler<MessageFromServer> for ClientSocketConnection {
     type Result = ();
 
     fn handle(&mut self, msg: MessageFromServer, ctx: &mut Self::Context) {
-        match msg.data {
-            MessageData::String(st) => ctx.text(st),
-            MessageData::Binary(bin) => ctx.binary(bin),
-        };
+        let mut frames = into_frames(msg.data, self.outbound_chunk_size);
+
+        if self.outbound_frames.is_empty() && frames.len() == 1 {
+            ctx.write_raw(frames.remove(0));
+            return;
+        }
+
+        if self.outbound_frames.is_empty() {
+            ctx.notify(WriteNextFrame);
+        }
+        self.outbound_frames.extend(frames);
+    }
+}
+
+impl Handler<WriteNextFrame> for ClientSocketConnection {
+    type Result = ();
+
+    fn handle(&mut self, _: WriteNextFrame, ctx: &mut Self::Context) {
+        if let Some(frame) = self.outbound_frames.pop_front() {
+            ctx.write_raw(frame);
+        }
+
+        if !self.outbound_frames.is_empty() {
+            ctx.notify(WriteNextFrame);
+        }
+    }
+}
+
+impl Handler<CloseConnection> for ClientSocketConnection {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        CloseConnection {
+            reason,
+            description,
+        }: CloseConnection,
+        ctx: &mut Self::Context,
+    ) {
+        tracing::info!(
+            client_id=?self.client_id,
+            ?reason,
+            ?description,
+            "Server is closing connection"
+        );
+
+        self.interval_handle.take().map(|d| ctx.cancel_future(d));
+
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some(truncate_close_description(
+                description.unwrap_or_else(|| format!("{:?}", reason)),
+            )),
+        }));
+        ctx.stop();
     }
 }
 
@@ -67,14 +194,14 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ClientSocketConne
             Ok(ws::Message::Text(text)) => {
                 let message = MessageFromClient::Message {
                     from_client: self.client_id,
-                    data: MessageData::String(text.to_string()),
+                    data: MessageData::String(text),
                 };
                 self.room.do_send(message);
             }
             Ok(ws::Message::Binary(data)) => {
                 let message = MessageFromClient::Message {
                     from_client: self.client_id,
-                    data: MessageData::Binary(data.to_vec()),
+                    data: MessageData::Binary(data),
                 };
                 self.room.do_send(message);
             }
@@ -84,10 +211,33 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ClientSocketConne
                     "User has disconnected from room",
                 );
 
-                self.close(ctx);
+                self.close(ctx, DisconnectReason::ClientClosed);
             }
             Err(error) => tracing::error!(?error, "Encountered error in StreamHandler"),
             _ => tracing::warn!(message=?msg, "Unhandled message in StreamHandler"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate_close_description() {
+        assert_eq!("short", truncate_close_description("short".to_string()));
+
+        let exact = "a".repeat(MAX_CLOSE_DESCRIPTION_LEN);
+        assert_eq!(exact, truncate_close_description(exact.clone()));
+
+        let long = "a".repeat(200);
+        assert_eq!(
+            MAX_CLOSE_DESCRIPTION_LEN,
+            truncate_close_description(long).len()
+        );
+
+        // "é" is two bytes, so the 123rd byte falls inside one.
+        let multibyte = format!("{}{}", "a".repeat(122), "é".repeat(10));
+        assert_eq!("a".repeat(122), truncate_close_description(multibyte));
+    }
+}
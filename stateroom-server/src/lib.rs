@@ -1,23 +1,44 @@
 mod client_socket_connection;
 mod connection_info;
+mod message_schema;
 mod messages;
+mod metrics;
 mod room_actor;
+mod room_id;
 mod server_state;
 mod service_actor;
+mod slow_start;
+mod stats;
+#[cfg(test)]
+mod test_util;
 
 use crate::room_actor::GetConnectionInfo;
 use actix_web::error::ErrorInternalServerError;
-use actix_web::web::{self, get, Query};
+use actix_web::http::header;
+use actix_web::web::{self, get, post, Query};
 use actix_web::{web::Data, App, Error, HttpRequest, HttpResponse, HttpServer, Result};
 use actix_web_actors::ws::WsResponseBuilder;
 pub use client_socket_connection::ClientSocketConnection;
 use connection_info::ConnectionInfo;
-pub use messages::{AssignClientId, MessageFromClient, MessageFromServer};
-pub use room_actor::RoomActor;
+pub use message_schema::{MessageSchema, MessageSchemaError};
+pub use messages::{
+    AssignClientId, AuthorizationResult, CloseConnection, MessageBatch, MessageFromClient,
+    MessageFromServer, ReleaseClientId, ReloadService, SetAcceptedMessages,
+};
+pub use metrics::{Direction, Histogram, Metrics, DEFAULT_PAYLOAD_SIZE_BUCKETS};
+pub use room_actor::{DrainClients, KickClient, RoomActor};
+pub use room_id::{
+    validate_room_id, RoomIdError, RoomIdGenerator, RoomIdPolicy, RoomIdTransform,
+    WordsRoomIdGenerator,
+};
 use serde::Deserialize;
 use server_state::ServerState;
 pub use service_actor::{ServiceActor, ServiceActorContext};
-use stateroom::{StateroomService, StateroomServiceFactory};
+pub use slow_start::{SlowStart, SlowStartLimiter};
+use stateroom::{ConnectionMetadata, StateroomService, StateroomServiceFactory};
+pub use stats::Stats;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 const DEFAULT_IP: &str = "0.0.0.0";
@@ -45,6 +66,68 @@ pub struct Server {
 
     /// A local filesystem path to serve from /client, or None (default).
     pub client_path: Option<String>,
+
+    /// Upper bounds (in bytes) of the buckets of the message payload size
+    /// histograms reported at `/metrics`.
+    ///
+    /// Defaults to [DEFAULT_PAYLOAD_SIZE_BUCKETS].
+    pub payload_size_buckets: Vec<u64>,
+
+    /// Whether messages sent to a client before its connection is fully
+    /// established are held and delivered once it is, rather than dropped.
+    ///
+    /// Defaults to true.
+    pub buffer_until_connected: bool,
+
+    /// The maximum number of pending connections the operating system queues
+    /// for the server to accept, or `None` to use actix-web's default (2048).
+    pub accept_backlog: Option<u32>,
+
+    /// Limits the rate at which WebSocket connections are accepted after the
+    /// server starts. Connections over the limit are refused with a `503`
+    /// status and a `Retry-After` header. Defaults to `None` (no limit).
+    pub slow_start: Option<SlowStart>,
+
+    /// Messages to clients longer than this many bytes are split into a series
+    /// of WebSocket frames of at most this size, so that sending a very large
+    /// message does not hold up the connection's event loop. Clients receive
+    /// them as a single message. Defaults to `None` (no splitting).
+    pub outbound_chunk_size: Option<usize>,
+
+    /// A secret that must be presented as a bearer token (an
+    /// `Authorization: Bearer <token>` header) to use the `/stats` and
+    /// `/reload` endpoints. If `None` (default), neither is served.
+    pub admin_token: Option<String>,
+
+    /// Applied to the room's key before it is passed to the service as its
+    /// room ID. The server hosts a single room, whose key is the empty string.
+    /// If `None` (default), the service receives the key unchanged.
+    pub room_id_transform: Option<RoomIdTransform>,
+
+    /// The rules the transformed room ID must follow, checked (and the ID
+    /// normalized) with [validate_room_id] when the server starts. Only applied
+    /// if [Server::room_id_transform] is set, since the untransformed key is
+    /// empty.
+    pub room_id_policy: RoomIdPolicy,
+
+    /// The path the status endpoint is served at, or `None` to not serve it,
+    /// for example so that a custom handler or a static file can be served
+    /// there instead.
+    ///
+    /// Defaults to `/status`.
+    pub status_path: Option<String>,
+
+    /// If set, text messages from clients that do not conform to the schema
+    /// are rejected before they reach the service, and the sender is sent an
+    /// error. Defaults to `None`.
+    pub message_schema: Option<MessageSchema>,
+
+    /// Whether to close the connection of a client that sends a type of message
+    /// (text or binary) the service does not accept, rather than just dropping
+    /// the message. See [stateroom::StateroomService::accepted_messages].
+    ///
+    /// Defaults to `false`.
+    pub close_on_unsupported_message: bool,
 }
 
 impl Default for Server {
@@ -56,6 +139,17 @@ impl Default for Server {
             ip: DEFAULT_IP.to_string(),
             static_path: None,
             client_path: None,
+            payload_size_buckets: DEFAULT_PAYLOAD_SIZE_BUCKETS.to_vec(),
+            buffer_until_connected: true,
+            accept_backlog: None,
+            slow_start: None,
+            outbound_chunk_size: None,
+            admin_token: None,
+            room_id_transform: None,
+            room_id_policy: RoomIdPolicy::default(),
+            status_path: Some("/status".to_string()),
+            message_schema: None,
+            close_on_unsupported_message: false,
         }
     }
 }
@@ -103,56 +197,154 @@ impl Server {
         self.ip = ip;
         self
     }
+
+    #[must_use]
+    pub fn with_payload_size_buckets(mut self, payload_size_buckets: Vec<u64>) -> Self {
+        self.payload_size_buckets = payload_size_buckets;
+        self
+    }
+
+    #[must_use]
+    pub fn with_buffer_until_connected(mut self, buffer_until_connected: bool) -> Self {
+        self.buffer_until_connected = buffer_until_connected;
+        self
+    }
+
+    #[must_use]
+    pub fn with_accept_backlog(mut self, accept_backlog: u32) -> Self {
+        self.accept_backlog = Some(accept_backlog);
+        self
+    }
+
+    #[must_use]
+    pub fn with_slow_start(mut self, slow_start: SlowStart) -> Self {
+        self.slow_start = Some(slow_start);
+        self
+    }
+
+    #[must_use]
+    pub fn with_outbound_chunk_size(mut self, outbound_chunk_size: usize) -> Self {
+        self.outbound_chunk_size = Some(outbound_chunk_size);
+        self
+    }
+
+    #[must_use]
+    pub fn with_admin_token(mut self, admin_token: String) -> Self {
+        self.admin_token = Some(admin_token);
+        self
+    }
+
+    #[must_use]
+    pub fn with_room_id_transform(
+        mut self,
+        room_id_transform: impl Fn(&str) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.room_id_transform = Some(Arc::new(room_id_transform));
+        self
+    }
+
+    #[must_use]
+    pub fn with_room_id_policy(mut self, room_id_policy: RoomIdPolicy) -> Self {
+        self.room_id_policy = room_id_policy;
+        self
+    }
+
+    #[must_use]
+    pub fn with_status_path(mut self, status_path: Option<String>) -> Self {
+        self.status_path = status_path;
+        self
+    }
+
+    #[must_use]
+    pub fn with_message_schema(mut self, message_schema: MessageSchema) -> Self {
+        self.message_schema = Some(message_schema);
+        self
+    }
+
+    #[must_use]
+    pub fn with_close_on_unsupported_message(mut self, close: bool) -> Self {
+        self.close_on_unsupported_message = close;
+        self
+    }
+
     /// Start a server given a [StateroomService].
     ///
     /// This function blocks until the server is terminated. While it is running, the following
     /// endpoints are available:
-    /// - `/` (GET): return HTTP 200 if the server is running (useful as a baseline status check)
+    /// - `/status` (GET): return HTTP 200 if the server is running (useful as a baseline status
+    ///   check). The path can be changed or the endpoint disabled with [Server::status_path].
     /// - `/ws` (GET): initiate a WebSocket connection to the stateroom service.
+    /// - `/metrics` (GET): return server metrics in the Prometheus text format.
+    /// - `/stats` (GET): return aggregate server statistics as JSON, if an
+    ///   [admin token](Server::admin_token) is set. Requires the token.
+    /// - `/reload` (POST): rebuild the room's service after
+    ///   [refreshing](StateroomServiceFactory::refresh) the factory, for example to pick up a
+    ///   recompiled WebAssembly module (see [ReloadService]), if an admin token is set. Requires
+    ///   the token.
+    ///
+    /// When the process receives an interrupt (or, on Unix, a termination) signal, every client
+    /// is disconnected with [DisconnectReason::ServerShutdown](stateroom::DisconnectReason) before
+    /// the server stops.
+    ///
+    /// Returns an error if the service cannot be built, or the server cannot bind to its address.
     pub async fn serve_async<J>(
         self,
         service_factory: impl StateroomServiceFactory<ServiceActorContext, Service = J> + Send + 'static,
     ) -> std::io::Result<()>
-        where
-            J: StateroomService + Send + Sync + Unpin + 'static,
+    where
+        J: StateroomService + Send + Sync + Unpin + 'static,
     {
         let host = format!("{}:{}", self.ip, self.port);
-        let server_state = Data::new(ServerState::new(service_factory, self).unwrap());
-        let server = HttpServer::new(move || {
-            #[allow(unused_mut)] // mut only needed with crate feature `serve-static`.
-                let mut app = App::new()
+        let accept_backlog = self.accept_backlog;
+        let server_state = Data::new(ServerState::new(service_factory, self)?);
+        let room_addr = server_state.room_addr.clone();
+        let mut server = HttpServer::new(move || {
+            App::new()
                 .app_data(server_state.clone())
-                .route("/status", get().to(status))
-                .route("/ws", get().to(websocket));
+                .configure(|cfg| configure_routes(cfg, &server_state.settings))
+        })
+        .disable_signals();
 
-            #[cfg(feature = "serve-static")]
-            {
-                if let Some(client_path) = &server_state.settings.client_path {
-                    //let client_dir = Path::new(client_path).parent().unwrap();
-                    app = app.service(actix_files::Files::new("/client", client_path));
-                }
+        if let Some(accept_backlog) = accept_backlog {
+            server = server.backlog(accept_backlog);
+        }
 
-                if let Some(static_path) = &server_state.settings.static_path {
-                    app = app.service(
-                        actix_files::Files::new("/", static_path).index_file("index.html"),
-                    );
-                }
+        let server = server.bind(&host)?.run();
+        let shutdown_signal = shutdown_signal()?;
+        let handle = server.handle();
+        actix_web::rt::spawn(async move {
+            shutdown_signal.await;
+            tracing::info!("Disconnecting clients before shutting down");
+            if let Err(error) = room_addr.send(DrainClients).await {
+                tracing::error!(?error, "Could not disconnect clients");
             }
-
-            app
-        })
-            .bind(&host)?;
+            handle.stop(true).await;
+        });
 
         tracing::info!(%host, "Server is listening");
-        server.run().await
+        server.await
     }
 
     /// Start a server given a [StateroomService].
     ///
     /// This function blocks until the server is terminated. While it is running, the following
     /// endpoints are available:
-    /// - `/` (GET): return HTTP 200 if the server is running (useful as a baseline status check)
+    /// - `/status` (GET): return HTTP 200 if the server is running (useful as a baseline status
+    ///   check). The path can be changed or the endpoint disabled with [Server::status_path].
     /// - `/ws` (GET): initiate a WebSocket connection to the stateroom service.
+    /// - `/metrics` (GET): return server metrics in the Prometheus text format.
+    /// - `/stats` (GET): return aggregate server statistics as JSON, if an
+    ///   [admin token](Server::admin_token) is set. Requires the token.
+    /// - `/reload` (POST): rebuild the room's service after
+    ///   [refreshing](StateroomServiceFactory::refresh) the factory, for example to pick up a
+    ///   recompiled WebAssembly module (see [ReloadService]), if an admin token is set. Requires
+    ///   the token.
+    ///
+    /// When the process receives an interrupt (or, on Unix, a termination) signal, every client
+    /// is disconnected with [DisconnectReason::ServerShutdown](stateroom::DisconnectReason) before
+    /// the server stops.
+    ///
+    /// Returns an error if the service cannot be built, or the server cannot bind to its address.
     pub fn serve<J>(
         self,
         service_factory: impl StateroomServiceFactory<ServiceActorContext, Service = J> + Send + 'static,
@@ -160,9 +352,57 @@ impl Server {
     where
         J: StateroomService + Send + Sync + Unpin + 'static,
     {
-        actix_web::rt::System::new().block_on(async move {
-            self.serve_async(service_factory).await
-        })
+        actix_web::rt::System::new()
+            .block_on(async move { self.serve_async(service_factory).await })
+    }
+}
+
+/// Returns a future that resolves when the process is asked to shut down.
+#[cfg(unix)]
+fn shutdown_signal() -> std::io::Result<impl std::future::Future<Output = ()>> {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    Ok(std::future::poll_fn(move |cx| {
+        if interrupt.poll_recv(cx).is_ready() || terminate.poll_recv(cx).is_ready() {
+            std::task::Poll::Ready(())
+        } else {
+            std::task::Poll::Pending
+        }
+    }))
+}
+
+/// Returns a future that resolves when the process is asked to shut down.
+#[cfg(not(unix))]
+#[allow(clippy::unnecessary_wraps)]
+fn shutdown_signal() -> std::io::Result<impl std::future::Future<Output = ()>> {
+    Ok(async {
+        let _ = actix_web::rt::signal::ctrl_c().await;
+    })
+}
+
+/// Registers the server's endpoints.
+fn configure_routes(cfg: &mut web::ServiceConfig, settings: &Server) {
+    if let Some(status_path) = &settings.status_path {
+        cfg.route(status_path, get().to(status));
+    }
+
+    cfg.route("/ws", get().to(websocket))
+        .route("/metrics", get().to(metrics))
+        .route("/stats", get().to(stats))
+        .route("/reload", post().to(reload));
+
+    #[cfg(feature = "serve-static")]
+    {
+        if let Some(client_path) = &settings.client_path {
+            //let client_dir = Path::new(client_path).parent().unwrap();
+            cfg.service(actix_files::Files::new("/client", client_path));
+        }
+
+        if let Some(static_path) = &settings.static_path {
+            cfg.service(actix_files::Files::new("/", static_path).index_file("index.html"));
+        }
     }
 }
 
@@ -174,8 +414,18 @@ struct WebsocketRequest {
 async fn websocket(req: HttpRequest, stream: web::Payload) -> actix_web::Result<HttpResponse> {
     let server_state: &Data<ServerState> = req.app_data().expect("Could not load ServerState.");
 
+    if let Some(slow_start) = &server_state.slow_start {
+        if !slow_start.try_acquire(Instant::now()) {
+            tracing::warn!("Refusing connection during slow start");
+            return Ok(HttpResponse::ServiceUnavailable()
+                .insert_header(("Retry-After", "1"))
+                .finish());
+        }
+    }
+
     let Query(WebsocketRequest { token }) =
         Query::<WebsocketRequest>::from_query(req.query_string())?;
+    let Query(query) = Query::<BTreeMap<String, String>>::from_query(req.query_string())?;
 
     let room_addr = server_state.room_addr.clone();
     let client_id = room_addr
@@ -191,6 +441,8 @@ async fn websocket(req: HttpRequest, stream: web::Payload) -> actix_web::Result<
             heartbeat_interval: server_state.settings.heartbeat_interval,
             heartbeat_timeout: server_state.settings.heartbeat_timeout,
             interval_handle: None,
+            outbound_chunk_size: server_state.settings.outbound_chunk_size,
+            outbound_frames: VecDeque::new(),
         },
         &req,
         stream,
@@ -199,11 +451,19 @@ async fn websocket(req: HttpRequest, stream: web::Payload) -> actix_web::Result<
     {
         Ok((addr, resp)) => {
             tracing::info!(?client_id, "New connection",);
-            room_addr.do_send(MessageFromClient::Connect(client_id, addr.recipient()));
+            room_addr.do_send(MessageFromClient::Connect(
+                client_id,
+                addr.clone().recipient(),
+                addr.recipient(),
+                ConnectionMetadata { query },
+            ));
 
             Ok(resp)
         }
-        Err(e) => Err(e),
+        Err(e) => {
+            room_addr.do_send(ReleaseClientId { client_id });
+            Err(e)
+        }
     }
 }
 
@@ -218,3 +478,641 @@ async fn status(req: HttpRequest) -> Result<web::Json<ConnectionInfo>, Error> {
 
     Ok(web::Json(connection_info))
 }
+
+/// Returns the response to send instead if the request does not present the
+/// admin token, or if no admin token is set.
+fn reject_unless_admin(req: &HttpRequest, settings: &Server) -> Option<HttpResponse> {
+    let admin_token = match &settings.admin_token {
+        Some(admin_token) => admin_token,
+        None => return Some(HttpResponse::NotFound().finish()),
+    };
+
+    let presented = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if !presented.map_or(false, |presented| {
+        stats::token_matches(presented, admin_token)
+    }) {
+        return Some(HttpResponse::Unauthorized().finish());
+    }
+
+    None
+}
+
+async fn stats(req: HttpRequest) -> HttpResponse {
+    let server_state: &Data<ServerState> = req.app_data().expect("Could not load ServerState.");
+
+    if let Some(rejection) = reject_unless_admin(&req, &server_state.settings) {
+        return rejection;
+    }
+
+    HttpResponse::Ok().json(Stats::collect(
+        &server_state.metrics,
+        &server_state.message_rate,
+        server_state.started,
+        Instant::now(),
+    ))
+}
+
+async fn reload(req: HttpRequest) -> HttpResponse {
+    let server_state: &Data<ServerState> = req.app_data().expect("Could not load ServerState.");
+
+    if let Some(rejection) = reject_unless_admin(&req, &server_state.settings) {
+        return rejection;
+    }
+
+    match server_state.reload_recipient.send(ReloadService).await {
+        Ok(Ok(())) => HttpResponse::Ok().finish(),
+        Ok(Err(error)) => HttpResponse::UnprocessableEntity().body(error),
+        Err(error) => {
+            tracing::error!(?error, "Could not reach service to reload it");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+async fn metrics(req: HttpRequest) -> HttpResponse {
+    let server_state: &Data<ServerState> = req.app_data().expect("Could not load ServerState.");
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(server_state.metrics.render())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stateroom::{ClientId, SimpleStateroomService, StateroomContext, WrappedStateroomService};
+    use std::convert::Infallible;
+    use std::io::{Read, Write};
+
+    #[derive(Clone)]
+    struct NoopService;
+
+    impl SimpleStateroomService for NoopService {
+        fn new(_: &str, _: &impl StateroomContext) -> Self {
+            NoopService
+        }
+    }
+
+    struct FailingFactory;
+
+    impl StateroomServiceFactory<ServiceActorContext> for FailingFactory {
+        type Service = WrappedStateroomService<NoopService, ServiceActorContext>;
+        type Error = &'static str;
+
+        fn build(&self, _: &str, _: ServiceActorContext) -> Result<Self::Service, Self::Error> {
+            Err("no service here")
+        }
+    }
+
+    /// Records the room ID it builds a service for.
+    #[derive(Default)]
+    struct RoomIdRecorder(Arc<std::sync::Mutex<Option<String>>>);
+
+    impl StateroomServiceFactory<ServiceActorContext> for RoomIdRecorder {
+        type Service = WrappedStateroomService<NoopService, ServiceActorContext>;
+        type Error = Infallible;
+
+        fn build(
+            &self,
+            room_id: &str,
+            context: ServiceActorContext,
+        ) -> Result<Self::Service, Self::Error> {
+            *self.0.lock().unwrap() = Some(room_id.to_string());
+            Ok(WrappedStateroomService::new(NoopService, context))
+        }
+    }
+
+    fn local_server(port: u16) -> Server {
+        Server::default()
+            .with_ip("127.0.0.1".to_string())
+            .with_port(port.into())
+    }
+
+    #[test]
+    fn test_bind_failure_returns_error() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let error = local_server(port).serve(NoopService).unwrap_err();
+        assert_eq!(std::io::ErrorKind::AddrInUse, error.kind());
+    }
+
+    #[test]
+    fn test_service_build_failure_returns_error() {
+        let error = local_server(0).serve(FailingFactory).unwrap_err();
+        assert!(error.to_string().contains("no service here"));
+    }
+
+    #[actix::test]
+    async fn test_room_id_transform() {
+        let factory = RoomIdRecorder::default();
+        let room_id = factory.0.clone();
+        let settings =
+            Server::default().with_room_id_transform(|key| format!("Tenant-A_{}lobby", key));
+
+        // The transformed ID is normalized before it reaches the service.
+        let _server_state = ServerState::new(factory, settings).unwrap();
+        assert_eq!(Some("tenant-a_lobby".to_string()), *room_id.lock().unwrap());
+    }
+
+    #[actix::test]
+    async fn test_invalid_room_id_fails_startup() {
+        for (transform, expected) in [
+            ("tenant/a", RoomIdError::InvalidCharacter('/')),
+            ("stats", RoomIdError::Reserved("stats".to_string())),
+        ] {
+            let settings = Server::default().with_room_id_transform(move |_| transform.to_string());
+            let error = ServerState::new(NoopService, settings).err().unwrap();
+
+            assert_eq!(std::io::ErrorKind::InvalidInput, error.kind());
+            assert_eq!(
+                Some(&expected),
+                error.get_ref().and_then(|error| error.downcast_ref())
+            );
+        }
+
+        let settings = Server::default()
+            .with_room_id_transform(|_| "stats".to_string())
+            .with_room_id_policy(RoomIdPolicy {
+                reserved: Vec::new(),
+                ..RoomIdPolicy::default()
+            });
+        assert!(ServerState::new(NoopService, settings).is_ok());
+    }
+
+    #[actix::test]
+    async fn test_status_path_moved() {
+        for (status_path, expected) in [
+            (Some("/health"), [("/health", 200), ("/status", 404)]),
+            (None, [("/health", 404), ("/status", 404)]),
+        ] {
+            let settings = Server::default().with_status_path(status_path.map(str::to_string));
+            let server_state = Data::new(ServerState::new(NoopService, settings).unwrap());
+            let app = actix_web::test::init_service(
+                App::new()
+                    .app_data(server_state.clone())
+                    .configure(|cfg| configure_routes(cfg, &server_state.settings)),
+            )
+            .await;
+
+            for (path, status) in expected {
+                let request = actix_web::test::TestRequest::get().uri(path).to_request();
+                let response = actix_web::test::call_service(&app, request).await;
+                assert_eq!(
+                    status,
+                    response.status().as_u16(),
+                    "{:?} {}",
+                    status_path,
+                    path
+                );
+            }
+        }
+    }
+
+    #[cfg(feature = "serve-static")]
+    #[actix::test]
+    async fn test_status_path() {
+        let static_dir =
+            std::env::temp_dir().join(format!("stateroom-server-static-{}", std::process::id()));
+        std::fs::create_dir_all(&static_dir).unwrap();
+        std::fs::write(static_dir.join("index.html"), "<h1>Custom index</h1>").unwrap();
+
+        for (status_path, expect_index) in [(Some("/"), false), (None, true)] {
+            let settings = Server::default()
+                .with_static_path(Some(static_dir.to_str().unwrap().to_string()))
+                .with_status_path(status_path.map(str::to_string));
+            let server_state = Data::new(ServerState::new(NoopService, settings).unwrap());
+            let app = actix_web::test::init_service(
+                App::new()
+                    .app_data(server_state.clone())
+                    .configure(|cfg| configure_routes(cfg, &server_state.settings)),
+            )
+            .await;
+
+            let request = actix_web::test::TestRequest::get().uri("/").to_request();
+            let body = actix_web::test::call_and_read_body(&app, request).await;
+            assert_eq!(expect_index, body == "<h1>Custom index</h1>", "{:?}", body);
+        }
+
+        std::fs::remove_dir_all(static_dir).unwrap();
+    }
+
+    #[actix::test]
+    async fn test_stats() {
+        let settings = Server::default().with_admin_token("secret".to_string());
+        let server_state = Data::new(ServerState::new(NoopService, settings).unwrap());
+
+        for message in ["one", "two", "three"] {
+            server_state
+                .room_addr
+                .send(MessageFromServer::new(
+                    stateroom::MessageRecipient::Broadcast,
+                    message.to_string(),
+                ))
+                .await
+                .unwrap();
+        }
+
+        let request = |token: Option<&str>| {
+            let mut request = actix_web::test::TestRequest::get()
+                .uri("/stats")
+                .app_data(server_state.clone());
+            if let Some(token) = token {
+                request = request.insert_header((header::AUTHORIZATION, token));
+            }
+            request.to_http_request()
+        };
+
+        for token in [None, Some("secret"), Some("Bearer wrong")] {
+            let response = stats(request(token)).await;
+            assert_eq!(actix_web::http::StatusCode::UNAUTHORIZED, response.status());
+        }
+
+        let response = stats(request(Some("Bearer secret"))).await;
+        assert_eq!(actix_web::http::StatusCode::OK, response.status());
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let stats: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(1, stats["active_rooms"]);
+        assert_eq!(1, stats["rooms_created"]);
+        assert_eq!(0, stats["connected_clients"]);
+        assert_eq!(3, stats["messages_total"]);
+        assert!(stats["messages_per_second"].as_f64().unwrap() > 0.0);
+        assert_eq!(env!("CARGO_PKG_VERSION"), stats["version"]);
+    }
+
+    #[actix::test]
+    async fn test_failed_upgrade_releases_client_id() {
+        let server_state = Data::new(ServerState::new(NoopService, Server::default()).unwrap());
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(server_state.clone())
+                .configure(|cfg| configure_routes(cfg, &server_state.settings)),
+        )
+        .await;
+
+        // A plain GET without the WebSocket upgrade headers.
+        for _ in 0..3 {
+            let request = actix_web::test::TestRequest::get().uri("/ws").to_request();
+            let response = actix_web::test::call_service(&app, request).await;
+            assert!(response.status().is_client_error());
+        }
+
+        let room = &server_state.room_addr;
+        assert_eq!(0, room.send(room_actor::CountPending).await.unwrap());
+        assert_eq!(
+            ClientId::from(1),
+            room.send(AssignClientId { token: None }).await.unwrap()
+        );
+    }
+
+    #[actix::test]
+    async fn test_stats_disabled_without_admin_token() {
+        let server_state = Data::new(ServerState::new(NoopService, Server::default()).unwrap());
+        let request = actix_web::test::TestRequest::get()
+            .uri("/stats")
+            .app_data(server_state)
+            .to_http_request();
+
+        let response = stats(request).await;
+        assert_eq!(actix_web::http::StatusCode::NOT_FOUND, response.status());
+    }
+
+    /// Greets each client with a message too large for a single chunk.
+    #[derive(Clone)]
+    struct LargeGreetingService;
+
+    fn large_greeting() -> String {
+        (0..10_000)
+            .map(|i| char::from(b'a' + (i % 26) as u8))
+            .collect()
+    }
+
+    impl SimpleStateroomService for LargeGreetingService {
+        fn new(_: &str, _: &impl StateroomContext) -> Self {
+            LargeGreetingService
+        }
+
+        fn connect(&mut self, client: ClientId, context: &impl StateroomContext) {
+            context.send_message(client, &large_greeting());
+        }
+    }
+
+    /// Reads a WebSocket frame sent by the server, returning whether it is the
+    /// final frame of a message, its opcode, and its payload.
+    fn read_frame(stream: &mut impl Read) -> (bool, u8, Vec<u8>) {
+        let mut header = [0; 2];
+        stream.read_exact(&mut header).unwrap();
+
+        let len = match header[1] & 0x7F {
+            126 => {
+                let mut len = [0; 2];
+                stream.read_exact(&mut len).unwrap();
+                u64::from(u16::from_be_bytes(len))
+            }
+            127 => {
+                let mut len = [0; 8];
+                stream.read_exact(&mut len).unwrap();
+                u64::from_be_bytes(len)
+            }
+            len => u64::from(len),
+        };
+
+        let mut payload = vec![0; len as usize];
+        stream.read_exact(&mut payload).unwrap();
+        (header[0] & 0x80 != 0, header[0] & 0x0F, payload)
+    }
+
+    #[test]
+    fn test_large_message_sent_in_chunks() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let server = local_server(port).with_outbound_chunk_size(1000);
+        std::thread::spawn(move || server.serve(LargeGreetingService));
+
+        let start = Instant::now();
+        let mut stream = loop {
+            match std::net::TcpStream::connect(("127.0.0.1", port)) {
+                Ok(stream) => break stream,
+                Err(_) if start.elapsed() < Duration::from_secs(5) => {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                Err(error) => panic!("Could not connect to server: {:?}", error),
+            }
+        };
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        write!(
+            stream,
+            "GET /ws HTTP/1.1\r\nHost: 127.0.0.1\r\nUpgrade: websocket\r\n\
+            Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Version: 13\r\n\r\n"
+        )
+        .unwrap();
+
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            stream.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        assert!(response.starts_with(b"HTTP/1.1 101"));
+
+        let mut frames = vec![read_frame(&mut stream)];
+        while !frames.last().unwrap().0 {
+            frames.push(read_frame(&mut stream));
+        }
+
+        // A text frame followed by continuation frames, none over the chunk size.
+        assert_eq!(10, frames.len());
+        assert_eq!(1, frames[0].1);
+        assert!(frames[1..].iter().all(|(_, opcode, _)| *opcode == 0));
+        assert!(frames.iter().all(|(_, _, payload)| payload.len() <= 1000));
+
+        let message: Vec<u8> = frames
+            .into_iter()
+            .flat_map(|(_, _, payload)| payload)
+            .collect();
+        assert_eq!(large_greeting().into_bytes(), message);
+    }
+
+    #[test]
+    fn test_module_missing_export_returns_error() {
+        // A module that implements every required export except `timer`.
+        let module = r#"(module
+            (memory (export "memory") 1)
+            (global (export "JAMSOCKET_API_VERSION") i32 (i32.const 16))
+            (global (export "JAMSOCKET_API_PROTOCOL") i32 (i32.const 20))
+            (data (i32.const 16) "\05\00\00\00\00\00\00\00")
+            (func (export "initialize") (param i32 i32))
+            (func (export "jam_malloc") (param i32) (result i32) (i32.const 1024))
+            (func (export "jam_free") (param i32 i32))
+            (func (export "connect") (param i32 i32 i32) (result i32) (i32.const 0))
+            (func (export "disconnect") (param i32 i32))
+            (func (export "message") (param i32 i32 i32))
+            (func (export "binary") (param i32 i32 i32))
+            (func (export "jam_snapshot") (param i32) (result i32) (i32.const 1))
+            (func (export "jam_restore") (param i32 i32) (result i32) (i32.const 1))
+        )"#;
+        let path = std::env::temp_dir().join(format!(
+            "stateroom-server-missing-export-{}.wat",
+            std::process::id()
+        ));
+        std::fs::write(&path, module).unwrap();
+
+        let factory = stateroom_wasm_host::WasmHostFactory::new(&path).unwrap();
+        let error = local_server(0).serve(factory).unwrap_err();
+        assert!(error.to_string().contains("timer"), "{}", error);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[actix::test]
+    async fn test_binary_rejected_for_text_only_module() {
+        use crate::messages::MessageData;
+        use crate::test_util::{Event, Recorder, TakeEvents};
+        use actix::Actor;
+        use stateroom::{AcceptedMessages, ConnectionMetadata, MessageRecipient};
+
+        // Broadcasts "text" from `message` and "binary" from `binary`.
+        let module = r#"(module
+            (import "env" "send_message" (func $send_message (param i32 i32 i32)))
+            (memory (export "memory") 1)
+            (global (export "JAMSOCKET_API_VERSION") i32 (i32.const 16))
+            (global (export "JAMSOCKET_API_PROTOCOL") i32 (i32.const 20))
+            (data (i32.const 16) "\05\00\00\00\00\00\00\00")
+            (data (i32.const 100) "textbinary")
+            (func (export "initialize") (param i32 i32))
+            (func (export "jam_malloc") (param i32) (result i32) (i32.const 1024))
+            (func (export "jam_free") (param i32 i32))
+            (func (export "connect") (param i32 i32 i32) (result i32) (i32.const 0))
+            (func (export "disconnect") (param i32 i32))
+            (func (export "timer"))
+            (func (export "message") (param i32 i32 i32)
+                (call $send_message (i32.const 0) (i32.const 100) (i32.const 4)))
+            (func (export "binary") (param i32 i32 i32)
+                (call $send_message (i32.const 0) (i32.const 104) (i32.const 6)))
+            (func (export "jam_snapshot") (param i32) (result i32) (i32.const 1))
+            (func (export "jam_restore") (param i32 i32) (result i32) (i32.const 1))
+        )"#;
+        let path = std::env::temp_dir().join(format!(
+            "stateroom-server-text-only-{}.wat",
+            std::process::id()
+        ));
+        std::fs::write(&path, module).unwrap();
+
+        let config = stateroom_wasm_host::WasmHostConfig::default()
+            .with_accepted_messages(AcceptedMessages::TEXT_ONLY);
+        let factory = stateroom_wasm_host::WasmHostFactory::new_with_config(&path, config).unwrap();
+        let server_state = ServerState::new(factory, Server::default()).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let client = Recorder::default().start();
+        let room = server_state.room_addr;
+        room.do_send(MessageFromClient::Connect(
+            1.into(),
+            client.clone().recipient(),
+            client.clone().recipient(),
+            ConnectionMetadata::default(),
+        ));
+
+        // The service runs on its own arbiter, so wait for it to accept the
+        // client, and then for its reply.
+        for _ in 0..100 {
+            if room
+                .send(GetConnectionInfo)
+                .await
+                .unwrap()
+                .active_connections
+                == 1
+            {
+                break;
+            }
+            actix::clock::sleep(Duration::from_millis(20)).await;
+        }
+
+        room.do_send(MessageFromClient::Message {
+            from_client: 1.into(),
+            data: MessageData::Binary(vec![1, 2, 3].into()),
+        });
+        room.do_send(MessageFromClient::Message {
+            from_client: 1.into(),
+            data: MessageData::String("hello".into()),
+        });
+
+        let mut events = Vec::new();
+        for _ in 0..100 {
+            events = client.send(TakeEvents).await.unwrap();
+            if !events.is_empty() {
+                break;
+            }
+            actix::clock::sleep(Duration::from_millis(20)).await;
+        }
+
+        assert_eq!(
+            vec![Event::FromServer(
+                MessageRecipient::Broadcast,
+                "text".to_string()
+            )],
+            events
+        );
+    }
+
+    #[actix::test]
+    async fn test_reload_serves_new_module() {
+        use crate::test_util::{Event, Recorder, TakeEvents};
+        use actix::Actor;
+        use stateroom::{ConnectionMetadata, MessageRecipient};
+
+        // Broadcasts `greeting` when a client connects.
+        let module = |greeting: &str| {
+            format!(
+                r#"(module
+                    (import "env" "send_message" (func $send_message (param i32 i32 i32)))
+                    (memory (export "memory") 1)
+                    (global (export "JAMSOCKET_API_VERSION") i32 (i32.const 16))
+                    (global (export "JAMSOCKET_API_PROTOCOL") i32 (i32.const 20))
+                    (data (i32.const 16) "\05\00\00\00\00\00\00\00")
+                    (data (i32.const 100) "{}")
+                    (func (export "initialize") (param i32 i32))
+                    (func (export "jam_malloc") (param i32) (result i32) (i32.const 1024))
+                    (func (export "jam_free") (param i32 i32))
+                    (func (export "connect") (param i32 i32 i32) (result i32)
+                        (call $send_message (i32.const 0) (i32.const 100) (i32.const {}))
+                        (i32.const 0))
+                    (func (export "disconnect") (param i32 i32))
+                    (func (export "timer"))
+                    (func (export "message") (param i32 i32 i32))
+                    (func (export "binary") (param i32 i32 i32))
+                    (func (export "jam_snapshot") (param i32) (result i32) (i32.const 1))
+                    (func (export "jam_restore") (param i32 i32) (result i32) (i32.const 1))
+                )"#,
+                greeting,
+                greeting.len()
+            )
+        };
+        let path = std::env::temp_dir().join(format!(
+            "stateroom-server-reload-{}.wat",
+            std::process::id()
+        ));
+        std::fs::write(&path, module("old")).unwrap();
+
+        let factory = stateroom_wasm_host::WasmHostFactory::new(&path).unwrap();
+        let settings = Server::default().with_admin_token("secret".to_string());
+        let server_state = Data::new(ServerState::new(factory, settings).unwrap());
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(server_state.clone())
+                .configure(|cfg| configure_routes(cfg, &server_state.settings)),
+        )
+        .await;
+
+        let client = Recorder::default().start();
+        server_state.room_addr.do_send(MessageFromClient::Connect(
+            1.into(),
+            client.clone().recipient(),
+            client.clone().recipient(),
+            ConnectionMetadata::default(),
+        ));
+        let next_events = || async {
+            for _ in 0..100 {
+                let events = client.send(TakeEvents).await.unwrap();
+                if !events.is_empty() {
+                    return events;
+                }
+                actix::clock::sleep(Duration::from_millis(20)).await;
+            }
+            Vec::new()
+        };
+        let greeting = |text: &str| {
+            vec![Event::FromServer(
+                MessageRecipient::Broadcast,
+                text.to_string(),
+            )]
+        };
+        assert_eq!(greeting("old"), next_events().await);
+
+        let reload = |token: Option<&str>| {
+            let mut request = actix_web::test::TestRequest::post().uri("/reload");
+            if let Some(token) = token {
+                request =
+                    request.insert_header((header::AUTHORIZATION, format!("Bearer {}", token)));
+            }
+            actix_web::test::call_service(&app, request.to_request())
+        };
+
+        std::fs::write(&path, module("new")).unwrap();
+        assert_eq!(
+            actix_web::http::StatusCode::UNAUTHORIZED,
+            reload(None).await.status()
+        );
+        assert_eq!(
+            actix_web::http::StatusCode::OK,
+            reload(Some("secret")).await.status()
+        );
+
+        // The module has no snapshot support, so the rebuilt service starts
+        // afresh and is told about the connected client.
+        assert_eq!(greeting("new"), next_events().await);
+
+        // A module that fails to load is not swapped in.
+        std::fs::write(&path, "(module)").unwrap();
+        assert_eq!(
+            actix_web::http::StatusCode::UNPROCESSABLE_ENTITY,
+            reload(Some("secret")).await.status()
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
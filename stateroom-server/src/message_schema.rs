@@ -0,0 +1,67 @@
+use std::{collections::BTreeSet, error::Error, fmt::Display};
+
+/// Describes the text messages a service accepts from clients. Each message
+/// must be a JSON object whose `tag_field` is a string in `tags`. Messages that
+/// do not conform are rejected by the room before they reach the service.
+///
+/// Binary messages are not checked.
+#[derive(Clone, Debug)]
+pub struct MessageSchema {
+    /// The field of each message that holds its type tag, e.g. `type`.
+    pub tag_field: String,
+
+    /// The accepted type tags.
+    pub tags: BTreeSet<String>,
+}
+
+/// The reason a message was rejected by [MessageSchema::validate].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MessageSchemaError {
+    NotJsonObject,
+    MissingTag { tag_field: String },
+    UnknownTag(String),
+}
+
+impl Display for MessageSchemaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotJsonObject => write!(f, "Message is not a JSON object."),
+            Self::MissingTag { tag_field } => {
+                write!(f, "Message has no string field {:?}.", tag_field)
+            }
+            Self::UnknownTag(tag) => write!(f, "Message type {:?} is not accepted.", tag),
+        }
+    }
+}
+
+impl Error for MessageSchemaError {}
+
+impl MessageSchema {
+    #[must_use]
+    pub fn new(tag_field: &str, tags: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        MessageSchema {
+            tag_field: tag_field.to_string(),
+            tags: tags.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Check that `message` conforms to the schema.
+    pub fn validate(&self, message: &str) -> Result<(), MessageSchemaError> {
+        let value: serde_json::Value =
+            serde_json::from_str(message).map_err(|_| MessageSchemaError::NotJsonObject)?;
+        let object = value.as_object().ok_or(MessageSchemaError::NotJsonObject)?;
+
+        let tag = object
+            .get(&self.tag_field)
+            .and_then(serde_json::Value::as_str)
+            .ok_or_else(|| MessageSchemaError::MissingTag {
+                tag_field: self.tag_field.clone(),
+            })?;
+
+        if self.tags.contains(tag) {
+            Ok(())
+        } else {
+            Err(MessageSchemaError::UnknownTag(tag.to_string()))
+        }
+    }
+}
@@ -1,14 +1,24 @@
 use actix::{Message, Recipient};
-use stateroom::{ClientId, MessageRecipient};
+use actix_web::web::Bytes;
+use bytestring::ByteString;
+use stateroom::{
+    AcceptedMessages, ClientId, ConnectionMetadata, DisconnectReason, MessageRecipient,
+};
 
 /// Represents a message or event initiated by a client.
 #[derive(Debug, Clone)]
 pub enum MessageFromClient {
-    /// A client opens a connection to the server.
-    Connect(ClientId, Recipient<MessageFromServer>),
+    /// A client opens a connection to the server, supplying metadata from
+    /// its connection request.
+    Connect(
+        ClientId,
+        Recipient<MessageFromServer>,
+        Recipient<CloseConnection>,
+        ConnectionMetadata,
+    ),
 
     /// A client disconnects from the server (or their connection otherwise drops.)
-    Disconnect(ClientId),
+    Disconnect(ClientId, DisconnectReason),
 
     /// A client sends a message.
     Message {
@@ -23,10 +33,30 @@ impl Message for MessageFromClient {
 
 /// Message received or to be sent over a WebSocket connection, which may be
 /// textual or binary.
+///
+/// The payload is reference-counted, so clones share it rather than copying
+/// it. A message broadcast to many clients is stored once, all the way to the
+/// WebSocket frame written to each client.
 #[derive(Debug, Clone)]
 pub enum MessageData {
-    String(String),
-    Binary(Vec<u8>),
+    String(ByteString),
+    Binary(Bytes),
+}
+
+impl MessageData {
+    /// The size of the message payload, in bytes.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        match self {
+            MessageData::String(st) => st.len(),
+            MessageData::Binary(bin) => bin.len(),
+        }
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 /// Represents a message sent to one or more clients from the server.
@@ -45,7 +75,7 @@ impl MessageFromServer {
     pub fn new(to_client: MessageRecipient, data: String) -> Self {
         MessageFromServer {
             to_client,
-            data: MessageData::String(data),
+            data: MessageData::String(data.into()),
         }
     }
 
@@ -53,11 +83,70 @@ impl MessageFromServer {
     pub fn new_binary(to_client: MessageRecipient, data: Vec<u8>) -> Self {
         MessageFromServer {
             to_client,
-            data: MessageData::Binary(data),
+            data: MessageData::Binary(data.into()),
         }
     }
 }
 
+/// The messages a service sent during a single callback, such as one call to
+/// `timer` or `message`.
+///
+/// The room delivers a batch in full before it handles any other event, so
+/// every client receives its share of the batch contiguously and in the order
+/// the messages were sent, and no client joins or leaves partway through.
+#[derive(Debug, Clone)]
+pub struct MessageBatch(pub Vec<MessageFromServer>);
+
+impl Message for MessageBatch {
+    type Result = ();
+}
+
+/// Instructs a client connection to close itself, because the server has
+/// decided to end the connection.
+#[derive(Debug, Clone)]
+pub struct CloseConnection {
+    pub reason: DisconnectReason,
+    /// Sent to the client as the description of the WebSocket close frame. If
+    /// `None`, the name of `reason` is sent instead.
+    pub description: Option<String>,
+}
+
+impl Message for CloseConnection {
+    type Result = ();
+}
+
+/// Instructs the service actor to rebuild its service from the factory, after
+/// asking the factory to [refresh](stateroom::StateroomServiceFactory::refresh).
+/// Returns a description of the problem if the service could not be rebuilt,
+/// in which case the current service keeps running.
+#[derive(Debug, Clone, Copy)]
+pub struct ReloadService;
+
+impl Message for ReloadService {
+    type Result = Result<(), String>;
+}
+
+/// Tells the room that the types of message the service accepts have changed.
+#[derive(Debug, Clone, Copy)]
+pub struct SetAcceptedMessages(pub AcceptedMessages);
+
+impl Message for SetAcceptedMessages {
+    type Result = ();
+}
+
+/// Reports whether the service accepted a newly connected client. Until the
+/// room receives this, the client is not sent messages addressed to others.
+#[derive(Debug, Clone)]
+pub struct AuthorizationResult {
+    pub client: ClientId,
+    /// An error containing the reason if the client was rejected.
+    pub result: Result<(), String>,
+}
+
+impl Message for AuthorizationResult {
+    type Result = ();
+}
+
 /// Represents a request to reserve a client ID and return it. Client IDs are
 /// unique only in the context of a room.
 ///
@@ -70,3 +159,13 @@ pub struct AssignClientId {
 impl Message for AssignClientId {
     type Result = ClientId;
 }
+
+/// Releases a client ID reserved with [AssignClientId] whose connection was
+/// never established, discarding anything buffered for it.
+pub struct ReleaseClientId {
+    pub client_id: ClientId,
+}
+
+impl Message for ReleaseClientId {
+    type Result = ();
+}
@@ -0,0 +1,323 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Default upper bounds (in bytes) of the message payload size histogram buckets.
+pub const DEFAULT_PAYLOAD_SIZE_BUCKETS: &[u64] =
+    &[64, 256, 1024, 4096, 16_384, 65_536, 262_144, 1_048_576];
+
+/// The direction of a message relative to the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// A message sent by a client to the service.
+    Inbound,
+    /// A message sent by the service to one or more clients.
+    Outbound,
+}
+
+impl Direction {
+    fn label(self) -> &'static str {
+        match self {
+            Direction::Inbound => "inbound",
+            Direction::Outbound => "outbound",
+        }
+    }
+}
+
+/// A histogram with fixed bucket boundaries, which can be rendered in the
+/// Prometheus text exposition format.
+pub struct Histogram {
+    /// Inclusive upper bounds of each bucket, in increasing order.
+    bounds: Vec<u64>,
+    /// Non-cumulative count of observations in each bucket. The final entry
+    /// counts observations larger than every bound.
+    counts: Vec<AtomicU64>,
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    #[must_use]
+    pub fn new(bounds: &[u64]) -> Self {
+        let mut bounds = bounds.to_vec();
+        bounds.sort_unstable();
+        bounds.dedup();
+
+        Histogram {
+            counts: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            bounds,
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: u64) {
+        let bucket = self.bounds.partition_point(|bound| *bound < value);
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Returns the cumulative count of observations less than or equal to each
+    /// bound, followed by the total count.
+    #[must_use]
+    pub fn cumulative_counts(&self) -> Vec<u64> {
+        self.counts
+            .iter()
+            .scan(0, |total, count| {
+                *total += count.load(Ordering::Relaxed);
+                Some(*total)
+            })
+            .collect()
+    }
+
+    fn render(&self, name: &str, labels: &str, out: &mut String) {
+        let counts = self.cumulative_counts();
+
+        for (bound, count) in self.bounds.iter().zip(&counts) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, bound, count
+            );
+        }
+
+        let total = counts.last().copied().unwrap_or_default();
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, total);
+        let _ = writeln!(
+            out,
+            "{}_sum{{{}}} {}",
+            name,
+            labels,
+            self.sum.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, total);
+    }
+}
+
+/// Counters describing the traffic handled by a server, exposed at `/metrics`.
+///
+/// Every value is updated atomically as events happen, so rendering the
+/// metrics does not need to wait on any actor.
+pub struct Metrics {
+    inbound_payload_bytes: Histogram,
+    outbound_payload_bytes: Histogram,
+    inbound_messages: AtomicU64,
+    outbound_messages: AtomicU64,
+    rooms_created: AtomicU64,
+    active_rooms: AtomicU64,
+    connected_clients: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new(DEFAULT_PAYLOAD_SIZE_BUCKETS)
+    }
+}
+
+impl Metrics {
+    /// Create a set of metrics, using the given bucket boundaries (in bytes)
+    /// for the payload size histograms.
+    #[must_use]
+    pub fn new(payload_size_buckets: &[u64]) -> Self {
+        Metrics {
+            inbound_payload_bytes: Histogram::new(payload_size_buckets),
+            outbound_payload_bytes: Histogram::new(payload_size_buckets),
+            inbound_messages: AtomicU64::new(0),
+            outbound_messages: AtomicU64::new(0),
+            rooms_created: AtomicU64::new(0),
+            active_rooms: AtomicU64::new(0),
+            connected_clients: AtomicU64::new(0),
+        }
+    }
+
+    #[must_use]
+    pub fn payload_bytes(&self, direction: Direction) -> &Histogram {
+        match direction {
+            Direction::Inbound => &self.inbound_payload_bytes,
+            Direction::Outbound => &self.outbound_payload_bytes,
+        }
+    }
+
+    /// The number of messages that have passed through the server in the given
+    /// direction. A message broadcast to several clients counts once.
+    #[must_use]
+    pub fn messages(&self, direction: Direction) -> u64 {
+        self.message_counter(direction).load(Ordering::Relaxed)
+    }
+
+    #[must_use]
+    pub fn rooms_created(&self) -> u64 {
+        self.rooms_created.load(Ordering::Relaxed)
+    }
+
+    #[must_use]
+    pub fn active_rooms(&self) -> u64 {
+        self.active_rooms.load(Ordering::Relaxed)
+    }
+
+    /// The number of clients that are connected and have been accepted by the
+    /// service.
+    #[must_use]
+    pub fn connected_clients(&self) -> u64 {
+        self.connected_clients.load(Ordering::Relaxed)
+    }
+
+    fn message_counter(&self, direction: Direction) -> &AtomicU64 {
+        match direction {
+            Direction::Inbound => &self.inbound_messages,
+            Direction::Outbound => &self.outbound_messages,
+        }
+    }
+
+    /// Record a message passing through the server, with a payload of `size` bytes.
+    pub fn record_payload(&self, direction: Direction, size: usize) {
+        self.message_counter(direction)
+            .fetch_add(1, Ordering::Relaxed);
+        self.payload_bytes(direction).observe(size as u64);
+    }
+
+    pub fn record_room_created(&self) {
+        self.rooms_created.fetch_add(1, Ordering::Relaxed);
+        self.active_rooms.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_room_closed(&self) {
+        decrement(&self.active_rooms, 1);
+    }
+
+    pub fn record_clients_connected(&self, count: u64) {
+        self.connected_clients.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn record_clients_disconnected(&self, count: u64) {
+        decrement(&self.connected_clients, count);
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    #[must_use]
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        render_value(
+            "stateroom_rooms_created_total",
+            "counter",
+            "Rooms created since the server started.",
+            self.rooms_created(),
+            &mut out,
+        );
+        render_value(
+            "stateroom_active_rooms",
+            "gauge",
+            "Rooms currently running.",
+            self.active_rooms(),
+            &mut out,
+        );
+        render_value(
+            "stateroom_connected_clients",
+            "gauge",
+            "Clients currently connected.",
+            self.connected_clients(),
+            &mut out,
+        );
+
+        out.push_str("# HELP stateroom_messages_total Messages processed.\n");
+        out.push_str("# TYPE stateroom_messages_total counter\n");
+        for direction in [Direction::Inbound, Direction::Outbound] {
+            let _ = writeln!(
+                out,
+                "stateroom_messages_total{{direction=\"{}\"}} {}",
+                direction.label(),
+                self.messages(direction)
+            );
+        }
+
+        out.push_str("# HELP stateroom_message_payload_bytes Size of message payloads.\n");
+        out.push_str("# TYPE stateroom_message_payload_bytes histogram\n");
+        for direction in [Direction::Inbound, Direction::Outbound] {
+            self.payload_bytes(direction).render(
+                "stateroom_message_payload_bytes",
+                &format!("direction=\"{}\"", direction.label()),
+                &mut out,
+            );
+        }
+
+        out
+    }
+}
+
+/// Subtract `amount` from `value`, stopping at zero.
+fn decrement(value: &AtomicU64, amount: u64) {
+    let _ = value.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+        Some(current.saturating_sub(amount))
+    });
+}
+
+fn render_value(name: &str, kind: &str, help: &str, value: u64, out: &mut String) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets() {
+        let metrics = Metrics::new(&[10, 100, 1000]);
+
+        for size in [0, 10, 11, 100, 5000] {
+            metrics.record_payload(Direction::Inbound, size);
+        }
+        metrics.record_payload(Direction::Outbound, 50);
+
+        assert_eq!(
+            vec![2, 4, 4, 5],
+            metrics
+                .payload_bytes(Direction::Inbound)
+                .cumulative_counts()
+        );
+        assert_eq!(
+            vec![0, 1, 1, 1],
+            metrics
+                .payload_bytes(Direction::Outbound)
+                .cumulative_counts()
+        );
+
+        let rendered = metrics.render();
+        assert!(rendered.contains(
+            "stateroom_message_payload_bytes_bucket{direction=\"inbound\",le=\"100\"} 4\n"
+        ));
+        assert!(rendered.contains(
+            "stateroom_message_payload_bytes_bucket{direction=\"inbound\",le=\"+Inf\"} 5\n"
+        ));
+        assert!(
+            rendered.contains("stateroom_message_payload_bytes_sum{direction=\"inbound\"} 5121\n")
+        );
+        assert!(
+            rendered.contains("stateroom_message_payload_bytes_count{direction=\"outbound\"} 1\n")
+        );
+    }
+
+    #[test]
+    fn test_counters_rendered() {
+        let metrics = Metrics::default();
+
+        metrics.record_room_created();
+        metrics.record_clients_connected(3);
+        metrics.record_clients_disconnected(1);
+        metrics.record_payload(Direction::Outbound, 10);
+        metrics.record_payload(Direction::Outbound, 20);
+
+        // Gauges do not go below zero.
+        metrics.record_room_closed();
+        metrics.record_room_closed();
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("# TYPE stateroom_rooms_created_total counter\n"));
+        assert!(rendered.contains("\nstateroom_rooms_created_total 1\n"));
+        assert!(rendered.contains("\nstateroom_active_rooms 0\n"));
+        assert!(rendered.contains("\nstateroom_connected_clients 2\n"));
+        assert!(rendered.contains("stateroom_messages_total{direction=\"inbound\"} 0\n"));
+        assert!(rendered.contains("stateroom_messages_total{direction=\"outbound\"} 2\n"));
+    }
+}
@@ -1,13 +1,18 @@
 use crate::{
     connection_info::ConnectionInfo,
-    messages::{AssignClientId, MessageFromClient, MessageFromServer},
+    message_schema::MessageSchema,
+    messages::{
+        AssignClientId, AuthorizationResult, CloseConnection, MessageBatch, MessageData,
+        MessageFromClient, MessageFromServer, ReleaseClientId, SetAcceptedMessages,
+    },
+    metrics::{Direction, Metrics},
 };
 use actix::{
     dev::MessageResponse, Actor, ActorContext, AsyncContext, Context, Handler, Message,
     MessageResult, Recipient, SpawnHandle,
 };
-use stateroom::{ClientId, MessageRecipient};
-use std::{collections::HashMap, time::SystemTime};
+use stateroom::{AcceptedMessages, ClientId, DisconnectReason, MessageRecipient};
+use std::{collections::HashMap, sync::Arc, time::SystemTime};
 
 /// Actor model representation of a “room”. A room is a set of clients
 /// that share an instance of a Stateroom instance. Conceptually, this
@@ -15,7 +20,10 @@ use std::{collections::HashMap, time::SystemTime};
 /// side-effects are isolated to the room in which they occur.
 pub struct RoomActor {
     service_actor: Option<Recipient<MessageFromClient>>,
-    connections: HashMap<ClientId, Recipient<MessageFromServer>>,
+    connections: HashMap<ClientId, ClientConnection>,
+    /// Clients whose connection has been established, but which the service
+    /// has not yet accepted.
+    awaiting: HashMap<ClientId, AwaitingClient>,
     /// User IDs are assigned sequentially within the context of each room,
     /// ensuring that they never overlap. `next_id` stores the next ID that
     /// will be assigned.
@@ -23,6 +31,51 @@ pub struct RoomActor {
     token_to_client: HashMap<String, ClientId>,
     shutdown_handle: Option<SpawnHandle>,
     inactive_since: Option<SystemTime>,
+    metrics: Arc<Metrics>,
+    /// Messages addressed to clients who have been assigned an ID but whose
+    /// connection has not yet been established, in the order they were sent.
+    /// `None` if buffering is disabled.
+    pending: Option<HashMap<ClientId, Vec<MessageFromServer>>>,
+    /// If set, text messages from clients that do not conform are rejected
+    /// instead of being passed to the service.
+    message_schema: Option<MessageSchema>,
+    /// Messages from clients of other types are dropped instead of being
+    /// passed to the service.
+    accepted_messages: AcceptedMessages,
+    /// Whether a client that sends a message of a type that is not accepted
+    /// is disconnected.
+    close_on_unsupported_message: bool,
+}
+
+/// The maximum number of messages buffered for a client whose connection has
+/// not yet been established. Further messages are dropped.
+const MAX_PENDING_MESSAGES: usize = 256;
+
+/// The addresses used to communicate with a connected client.
+struct ClientConnection {
+    messages: Recipient<MessageFromServer>,
+    close: Recipient<CloseConnection>,
+}
+
+/// A client whose connection has been established, while the room waits to
+/// hear whether the service accepts it.
+struct AwaitingClient {
+    connection: ClientConnection,
+    /// Messages sent to the client in the meantime, delivered in order if it
+    /// is accepted.
+    messages: Vec<MessageFromServer>,
+    /// Set if the client disconnected in the meantime.
+    disconnected: Option<DisconnectReason>,
+}
+
+impl AwaitingClient {
+    fn hold(&mut self, message: MessageFromServer) {
+        if self.messages.len() < MAX_PENDING_MESSAGES {
+            self.messages.push(message);
+        } else {
+            tracing::warn!("Dropping message to user who has not been accepted");
+        }
+    }
 }
 
 struct Shutdown;
@@ -35,53 +88,205 @@ impl Message for Shutdown {
 #[rtype(result = "ConnectionInfo")]
 pub struct GetConnectionInfo;
 
+/// Instructs the room to close a client's connection. The service is notified
+/// of the disconnection with the given reason.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct KickClient {
+    pub client: ClientId,
+    pub reason: DisconnectReason,
+}
+
+/// Instructs the room to close every client's connection because the server
+/// is shutting down. The service is notified of each disconnection with
+/// [DisconnectReason::ServerShutdown].
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct DrainClients;
+
 impl RoomActor {
     #[must_use]
-    pub fn new(service_actor: Recipient<MessageFromClient>) -> Self {
+    pub fn new(service_actor: Recipient<MessageFromClient>, metrics: Arc<Metrics>) -> Self {
         RoomActor {
             service_actor: Some(service_actor),
             connections: HashMap::default(),
+            awaiting: HashMap::default(),
             token_to_client: HashMap::default(),
             next_id: 1,
             shutdown_handle: None,
             inactive_since: Some(SystemTime::now()),
+            metrics,
+            pending: Some(HashMap::default()),
+            message_schema: None,
+            accepted_messages: AcceptedMessages::ALL,
+            close_on_unsupported_message: false,
         }
     }
-}
 
-impl Actor for RoomActor {
-    type Context = Context<Self>;
-}
+    /// Whether to hold messages sent to a client between the time its ID is
+    /// assigned and the time its connection is established, and deliver them
+    /// in order once it is. If disabled, such messages are dropped.
+    ///
+    /// Enabled by default.
+    #[must_use]
+    pub fn with_buffer_until_connected(mut self, enabled: bool) -> Self {
+        self.pending = if enabled {
+            Some(HashMap::default())
+        } else {
+            None
+        };
+        self
+    }
 
-impl Handler<MessageFromServer> for RoomActor {
-    type Result = ();
+    /// Reject text messages from clients that do not conform to `schema`. The
+    /// sender of a rejected message is sent a JSON object with an `error`
+    /// field describing the problem.
+    #[must_use]
+    pub fn with_message_schema(mut self, schema: MessageSchema) -> Self {
+        self.message_schema = Some(schema);
+        self
+    }
+
+    /// Drop text or binary messages from clients, if `accepted` does not
+    /// include them, instead of passing them to the service. Both types are
+    /// accepted by default.
+    #[must_use]
+    pub fn with_accepted_messages(mut self, accepted: AcceptedMessages) -> Self {
+        self.accepted_messages = accepted;
+        self
+    }
+
+    /// Close the connection of a client that sends a message of a type that is
+    /// not accepted (see [RoomActor::with_accepted_messages]).
+    #[must_use]
+    pub fn with_close_on_unsupported_message(mut self, close: bool) -> Self {
+        self.close_on_unsupported_message = close;
+        self
+    }
+
+    /// Close a client's connection, and tell the service that it disconnected.
+    /// If the service has not yet accepted the client, it is told once it does.
+    fn kick(&mut self, client: ClientId, reason: DisconnectReason, description: Option<String>) {
+        if let Some(awaiting) = self.awaiting.get_mut(&client) {
+            if awaiting.disconnected.is_none() {
+                awaiting.connection.close.do_send(CloseConnection {
+                    reason,
+                    description,
+                });
+                awaiting.disconnected = Some(reason);
+            }
+        } else if let Some(connection) = self.connections.remove(&client) {
+            self.metrics.record_clients_disconnected(1);
+            connection.close.do_send(CloseConnection {
+                reason,
+                description,
+            });
+
+            if let Some(service_actor) = &self.service_actor {
+                service_actor.do_send(MessageFromClient::Disconnect(client, reason));
+            }
+
+            self.mark_inactive_if_empty();
+        } else {
+            tracing::warn!(?client, "Tried to kick a client who is not connected");
+        }
+    }
+
+    fn send_to_client(&mut self, client_id: ClientId, message: MessageFromServer) {
+        if let Some(client_connection) = self.connections.get(&client_id) {
+            client_connection.messages.do_send(message);
+        } else if let Some(awaiting) = self.awaiting.get_mut(&client_id) {
+            awaiting.hold(message);
+        } else if let Some(buffer) = self
+            .pending
+            .as_mut()
+            .and_then(|pending| pending.get_mut(&client_id))
+        {
+            if buffer.len() < MAX_PENDING_MESSAGES {
+                buffer.push(message);
+            } else {
+                tracing::warn!(?client_id, "Dropping message to user who has not connected");
+            }
+        } else {
+            tracing::warn!(
+                ?client_id,
+                "Could not get address of user, who may have disconnected",
+            );
+        }
+    }
+
+    /// Delivers a message from the service to its recipients.
+    fn deliver(&mut self, message: MessageFromServer) {
+        self.metrics
+            .record_payload(Direction::Outbound, message.data.len());
 
-    fn handle(&mut self, message: MessageFromServer, _ctx: &mut Context<Self>) {
         match message.to_client {
             MessageRecipient::Broadcast => {
-                for addr in self.connections.values() {
-                    addr.do_send(message.clone());
+                for connection in self.connections.values() {
+                    connection.messages.do_send(message.clone());
+                }
+                for awaiting in self.awaiting.values_mut() {
+                    awaiting.hold(message.clone());
                 }
             }
             MessageRecipient::EveryoneExcept(skip_client_id) => {
-                for (client_id, addr) in self.connections.iter() {
+                for (client_id, connection) in self.connections.iter() {
                     if client_id != &skip_client_id {
-                        addr.do_send(message.clone());
+                        connection.messages.do_send(message.clone());
+                    }
+                }
+                for (client_id, awaiting) in self.awaiting.iter_mut() {
+                    if client_id != &skip_client_id {
+                        awaiting.hold(message.clone());
                     }
                 }
             }
-            MessageRecipient::Client(client_id) => {
-                if let Some(client_connection) = self.connections.get(&client_id) {
-                    client_connection.do_send(message);
-                } else {
-                    tracing::warn!(
-                        ?client_id,
-                        "Could not get address of user, who may have disconnected",
-                    );
+            MessageRecipient::Client(client_id) => self.send_to_client(client_id, message),
+            MessageRecipient::Clients(ref client_ids) => {
+                for client_id in client_ids {
+                    self.send_to_client(*client_id, message.clone());
                 }
             }
         }
     }
+
+    fn mark_inactive_if_empty(&mut self) {
+        if self.connections.is_empty() && self.awaiting.is_empty() {
+            self.inactive_since = Some(SystemTime::now());
+        }
+    }
+}
+
+impl Actor for RoomActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _: &mut Self::Context) {
+        self.metrics.record_room_created();
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        self.metrics
+            .record_clients_disconnected(self.connections.len() as u64);
+        self.metrics.record_room_closed();
+    }
+}
+
+impl Handler<MessageFromServer> for RoomActor {
+    type Result = ();
+
+    fn handle(&mut self, message: MessageFromServer, _ctx: &mut Context<Self>) {
+        self.deliver(message);
+    }
+}
+
+impl Handler<MessageBatch> for RoomActor {
+    type Result = ();
+
+    fn handle(&mut self, MessageBatch(messages): MessageBatch, _ctx: &mut Context<Self>) {
+        for message in messages {
+            self.deliver(message);
+        }
+    }
 }
 
 impl Handler<MessageFromClient> for RoomActor {
@@ -90,8 +295,26 @@ impl Handler<MessageFromClient> for RoomActor {
     fn handle(&mut self, message: MessageFromClient, ctx: &mut Context<Self>) {
         if let Some(service_actor) = &self.service_actor {
             match &message {
-                MessageFromClient::Connect(client, resp) => {
-                    self.connections.insert(*client, resp.clone());
+                MessageFromClient::Connect(client, messages, close, _) => {
+                    // Hold anything sent to this client before its connection was ready,
+                    // to be delivered once the service accepts it.
+                    let buffered = self
+                        .pending
+                        .as_mut()
+                        .and_then(|pending| pending.remove(client))
+                        .unwrap_or_default();
+
+                    self.awaiting.insert(
+                        *client,
+                        AwaitingClient {
+                            connection: ClientConnection {
+                                messages: messages.clone(),
+                                close: close.clone(),
+                            },
+                            messages: buffered,
+                            disconnected: None,
+                        },
+                    );
                     self.inactive_since = None;
                     service_actor.do_send(message);
 
@@ -99,16 +322,68 @@ impl Handler<MessageFromClient> for RoomActor {
                     // cancel that.
                     self.shutdown_handle.take().map(|t| ctx.cancel_future(t));
                 }
-                MessageFromClient::Disconnect(client_id) => {
-                    self.connections.remove(client_id);
-
-                    if self.connections.is_empty() {
-                        self.inactive_since = Some(SystemTime::now());
+                MessageFromClient::Disconnect(client_id, reason) => {
+                    if let Some(pending) = &mut self.pending {
+                        pending.remove(client_id);
                     }
 
-                    service_actor.do_send(message);
+                    // The connection may already have been removed if the server
+                    // closed it, in which case the service has already been told.
+                    if self.connections.remove(client_id).is_some() {
+                        self.metrics.record_clients_disconnected(1);
+                        service_actor.do_send(message);
+                        self.mark_inactive_if_empty();
+                    } else if let Some(awaiting) = self.awaiting.get_mut(client_id) {
+                        // The service is only told if it accepts the client.
+                        awaiting.disconnected = Some(*reason);
+                    }
                 }
-                MessageFromClient::Message { .. } => {
+                MessageFromClient::Message { data, from_client } => {
+                    self.metrics.record_payload(Direction::Inbound, data.len());
+
+                    let accepted = match data {
+                        MessageData::String(_) => self.accepted_messages.text,
+                        MessageData::Binary(_) => self.accepted_messages.binary,
+                    };
+                    if !accepted {
+                        tracing::warn!(
+                            client_id=?from_client,
+                            "Dropping message of unsupported type",
+                        );
+                        if self.close_on_unsupported_message {
+                            self.kick(
+                                *from_client,
+                                DisconnectReason::ServerKick,
+                                Some("Unsupported message type.".to_string()),
+                            );
+                        }
+                        return;
+                    }
+
+                    // Messages from clients the service has not accepted (or has
+                    // since been disconnected from) are not passed on.
+                    if !self.connections.contains_key(from_client) {
+                        tracing::warn!(
+                            client_id=?from_client,
+                            "Dropping message from client who is not connected",
+                        );
+                        return;
+                    }
+
+                    if let (Some(schema), MessageData::String(text)) = (&self.message_schema, data)
+                    {
+                        if let Err(error) = schema.validate(text) {
+                            tracing::warn!(client_id=?from_client, %error, "Rejecting message");
+                            let response = serde_json::json!({ "error": error.to_string() });
+                            let from_client = *from_client;
+                            self.send_to_client(
+                                from_client,
+                                MessageFromServer::new(from_client.into(), response.to_string()),
+                            );
+                            return;
+                        }
+                    }
+
                     service_actor.do_send(message);
                 }
             }
@@ -133,7 +408,61 @@ impl MessageResponse<RoomActor, AssignClientId> for ClientId {
 impl Handler<AssignClientId> for RoomActor {
     type Result = ClientId;
 
-    fn handle(&mut self, AssignClientId { token }: AssignClientId, _ctx: &mut Context<Self>) -> ClientId {
+    fn handle(
+        &mut self,
+        AssignClientId { token }: AssignClientId,
+        _ctx: &mut Context<Self>,
+    ) -> ClientId {
+        let client_id = self.assign_client_id(token);
+
+        if !self.connections.contains_key(&client_id) {
+            if let Some(pending) = &mut self.pending {
+                pending.entry(client_id).or_default();
+            }
+        }
+
+        client_id
+    }
+}
+
+impl Handler<ReleaseClientId> for RoomActor {
+    type Result = ();
+
+    fn handle(&mut self, ReleaseClientId { client_id }: ReleaseClientId, _: &mut Context<Self>) {
+        if self.connections.contains_key(&client_id) || self.awaiting.contains_key(&client_id) {
+            return;
+        }
+
+        if let Some(pending) = &mut self.pending {
+            pending.remove(&client_id);
+        }
+
+        // Reuse the ID if it was the last assigned, and no token refers to it.
+        if u32::from(client_id) + 1 == self.next_id
+            && !self.token_to_client.values().any(|&id| id == client_id)
+        {
+            self.next_id -= 1;
+        }
+    }
+}
+
+/// Returns the number of clients with buffered messages.
+#[cfg(test)]
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub(crate) struct CountPending;
+
+#[cfg(test)]
+impl Handler<CountPending> for RoomActor {
+    type Result = usize;
+
+    fn handle(&mut self, _: CountPending, _: &mut Context<Self>) -> usize {
+        self.pending.as_ref().map_or(0, HashMap::len)
+    }
+}
+
+impl RoomActor {
+    fn assign_client_id(&mut self, token: Option<String>) -> ClientId {
         if let Some(token) = &token {
             let entry = self.token_to_client.entry(token.clone());
             let next_id = &mut self.next_id;
@@ -146,7 +475,7 @@ impl Handler<AssignClientId> for RoomActor {
         } else {
             let result = self.next_id;
             self.next_id += 1;
-    
+
             result.into()
         }
     }
@@ -164,6 +493,88 @@ impl Handler<Shutdown> for RoomActor {
     }
 }
 
+impl Handler<KickClient> for RoomActor {
+    type Result = ();
+
+    fn handle(&mut self, KickClient { client, reason }: KickClient, _: &mut Self::Context) {
+        self.kick(client, reason, None);
+    }
+}
+
+impl Handler<DrainClients> for RoomActor {
+    type Result = ();
+
+    fn handle(&mut self, _: DrainClients, _: &mut Self::Context) {
+        let clients: Vec<ClientId> = self
+            .connections
+            .keys()
+            .chain(self.awaiting.keys())
+            .copied()
+            .collect();
+        for client in clients {
+            self.kick(client, DisconnectReason::ServerShutdown, None);
+        }
+    }
+}
+
+impl Handler<SetAcceptedMessages> for RoomActor {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        SetAcceptedMessages(accepted): SetAcceptedMessages,
+        _: &mut Self::Context,
+    ) {
+        self.accepted_messages = accepted;
+    }
+}
+
+impl Handler<AuthorizationResult> for RoomActor {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        AuthorizationResult { client, result }: AuthorizationResult,
+        _: &mut Self::Context,
+    ) {
+        let awaiting = if let Some(awaiting) = self.awaiting.remove(&client) {
+            awaiting
+        } else {
+            tracing::warn!(?client, "Received authorization result for unknown client");
+            return;
+        };
+
+        match (result, awaiting.disconnected) {
+            (Ok(()), None) => {
+                for message in awaiting.messages {
+                    awaiting.connection.messages.do_send(message);
+                }
+                self.connections.insert(client, awaiting.connection);
+                self.metrics.record_clients_connected(1);
+            }
+            (Ok(()), Some(reason)) => {
+                // The client left before it was accepted, but the service has seen
+                // it connect, so it needs to see it disconnect.
+                if let Some(service_actor) = &self.service_actor {
+                    service_actor.do_send(MessageFromClient::Disconnect(client, reason));
+                }
+            }
+            (Err(description), disconnected) => {
+                tracing::info!(?client, %description, "Service rejected client");
+
+                if disconnected.is_none() {
+                    awaiting.connection.close.do_send(CloseConnection {
+                        reason: DisconnectReason::Rejected,
+                        description: Some(description),
+                    });
+                }
+            }
+        }
+
+        self.mark_inactive_if_empty();
+    }
+}
+
 impl Handler<GetConnectionInfo> for RoomActor {
     type Result = MessageResult<GetConnectionInfo>;
 
@@ -180,3 +591,605 @@ impl Handler<GetConnectionInfo> for RoomActor {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{Event, Recorder};
+    use actix::{Actor, Addr};
+    use stateroom::ConnectionMetadata;
+
+    /// Connects a client to the room, standing in for a service that accepts it.
+    async fn connect(room: &Addr<RoomActor>, client_id: ClientId, client: &Addr<Recorder>) {
+        room.do_send(MessageFromClient::Connect(
+            client_id,
+            client.clone().recipient(),
+            client.clone().recipient(),
+            ConnectionMetadata::default(),
+        ));
+        room.send(AuthorizationResult {
+            client: client_id,
+            result: Ok(()),
+        })
+        .await
+        .unwrap();
+    }
+
+    #[actix::test]
+    async fn test_disconnect_reasons() {
+        let service = Recorder::default().start();
+        let client = Recorder::default().start();
+        let room = RoomActor::new(service.clone().recipient(), Arc::default()).start();
+
+        for id in [1, 2] {
+            connect(&room, id.into(), &client).await;
+        }
+
+        room.do_send(MessageFromClient::Disconnect(
+            1.into(),
+            DisconnectReason::ClientClosed,
+        ));
+        room.send(KickClient {
+            client: 2.into(),
+            reason: DisconnectReason::ServerKick,
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                Event::Connect(1.into()),
+                Event::Connect(2.into()),
+                Event::Disconnect(1.into(), DisconnectReason::ClientClosed),
+                Event::Disconnect(2.into(), DisconnectReason::ServerKick),
+            ],
+            service.send(crate::test_util::TakeEvents).await.unwrap()
+        );
+
+        // Only the kicked client is told to close by the server.
+        assert_eq!(
+            vec![Event::Close(DisconnectReason::ServerKick)],
+            client.send(crate::test_util::TakeEvents).await.unwrap()
+        );
+    }
+
+    #[actix::test]
+    async fn test_drain_clients() {
+        let service = Recorder::default().start();
+        let client = Recorder::default().start();
+        let room = RoomActor::new(service.clone().recipient(), Arc::default()).start();
+
+        connect(&room, 1.into(), &client).await;
+        room.do_send(MessageFromClient::Connect(
+            2.into(),
+            client.clone().recipient(),
+            client.clone().recipient(),
+            ConnectionMetadata::default(),
+        ));
+        room.send(DrainClients).await.unwrap();
+
+        assert_eq!(
+            vec![
+                Event::Connect(1.into()),
+                Event::Connect(2.into()),
+                Event::Disconnect(1.into(), DisconnectReason::ServerShutdown),
+            ],
+            service.send(crate::test_util::TakeEvents).await.unwrap()
+        );
+        assert_eq!(
+            vec![
+                Event::Close(DisconnectReason::ServerShutdown),
+                Event::Close(DisconnectReason::ServerShutdown),
+            ],
+            client.send(crate::test_util::TakeEvents).await.unwrap()
+        );
+    }
+
+    #[actix::test]
+    async fn test_payload_sizes_recorded() {
+        let service = Recorder::default().start();
+        let client = Recorder::default().start();
+        let metrics = Arc::new(Metrics::new(&[8, 64]));
+        let room = RoomActor::new(service.clone().recipient(), metrics.clone()).start();
+
+        connect(&room, 1.into(), &client).await;
+        room.do_send(MessageFromClient::Message {
+            from_client: 1.into(),
+            data: MessageData::String("hello".into()),
+        });
+        room.send(MessageFromServer::new_binary(
+            MessageRecipient::Broadcast,
+            vec![0; 100],
+        ))
+        .await
+        .unwrap();
+
+        assert_eq!(
+            vec![1, 1, 1],
+            metrics
+                .payload_bytes(Direction::Inbound)
+                .cumulative_counts()
+        );
+        assert_eq!(
+            vec![0, 0, 1],
+            metrics
+                .payload_bytes(Direction::Outbound)
+                .cumulative_counts()
+        );
+    }
+
+    #[actix::test]
+    async fn test_room_and_client_counts_recorded() {
+        let service = Recorder::default().start();
+        let client = Recorder::default().start();
+        let metrics = Arc::new(Metrics::default());
+        let room = RoomActor::new(service.recipient(), metrics.clone()).start();
+
+        connect(&room, 1.into(), &client).await;
+        connect(&room, 2.into(), &client).await;
+        assert_eq!(1, metrics.rooms_created());
+        assert_eq!(1, metrics.active_rooms());
+        assert_eq!(2, metrics.connected_clients());
+
+        room.do_send(MessageFromClient::Message {
+            from_client: 1.into(),
+            data: MessageData::String("hello".into()),
+        });
+        room.send(MessageFromClient::Disconnect(
+            2.into(),
+            DisconnectReason::ClientClosed,
+        ))
+        .await
+        .unwrap();
+        assert_eq!(1, metrics.connected_clients());
+        assert_eq!(1, metrics.messages(Direction::Inbound));
+        assert_eq!(0, metrics.messages(Direction::Outbound));
+    }
+
+    #[actix::test]
+    async fn test_send_to_everyone_except_sender() {
+        let service = Recorder::default().start();
+        let clients: Vec<_> = (0..3).map(|_| Recorder::default().start()).collect();
+        let room = RoomActor::new(service.recipient(), Arc::default()).start();
+
+        for (id, client) in (1..).zip(&clients) {
+            connect(&room, id.into(), client).await;
+        }
+
+        let recipient = MessageRecipient::EveryoneExcept(2.into());
+        room.send(MessageFromServer::new(recipient.clone(), "hi".to_string()))
+            .await
+            .unwrap();
+
+        let mut received = Vec::new();
+        for client in &clients {
+            received.push(client.send(crate::test_util::TakeEvents).await.unwrap());
+        }
+
+        assert_eq!(
+            vec![
+                vec![Event::FromServer(recipient.clone(), "hi".to_string())],
+                vec![],
+                vec![Event::FromServer(recipient, "hi".to_string())],
+            ],
+            received
+        );
+    }
+
+    #[actix::test]
+    async fn test_send_to_client_list() {
+        let service = Recorder::default().start();
+        let clients: Vec<_> = (0..3).map(|_| Recorder::default().start()).collect();
+        let room = RoomActor::new(service.recipient(), Arc::default()).start();
+
+        for (id, client) in (1..).zip(&clients) {
+            connect(&room, id.into(), client).await;
+        }
+
+        let recipient = MessageRecipient::Clients(vec![1.into(), 3.into()]);
+        room.do_send(MessageFromServer::new(recipient.clone(), "hi".to_string()));
+        room.send(MessageFromServer::new(
+            MessageRecipient::Clients(Vec::new()),
+            "nobody".to_string(),
+        ))
+        .await
+        .unwrap();
+
+        let mut received = Vec::new();
+        for client in &clients {
+            received.push(client.send(crate::test_util::TakeEvents).await.unwrap());
+        }
+
+        assert_eq!(
+            vec![
+                vec![Event::FromServer(recipient.clone(), "hi".to_string())],
+                vec![],
+                vec![Event::FromServer(recipient, "hi".to_string())],
+            ],
+            received
+        );
+    }
+
+    /// Stands in for a client connection, keeping the payloads it receives.
+    #[derive(Default)]
+    struct PayloadRecorder(Vec<MessageData>);
+
+    impl Actor for PayloadRecorder {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<MessageFromServer> for PayloadRecorder {
+        type Result = ();
+
+        fn handle(&mut self, msg: MessageFromServer, _: &mut Self::Context) {
+            self.0.push(msg.data);
+        }
+    }
+
+    impl Handler<CloseConnection> for PayloadRecorder {
+        type Result = ();
+
+        fn handle(&mut self, _: CloseConnection, _: &mut Self::Context) {}
+    }
+
+    struct TakePayloads;
+
+    impl Message for TakePayloads {
+        type Result = Vec<MessageData>;
+    }
+
+    impl Handler<TakePayloads> for PayloadRecorder {
+        type Result = MessageResult<TakePayloads>;
+
+        fn handle(&mut self, _: TakePayloads, _: &mut Self::Context) -> Self::Result {
+            MessageResult(std::mem::take(&mut self.0))
+        }
+    }
+
+    #[actix::test]
+    async fn test_broadcast_shares_payload() {
+        let service = Recorder::default().start();
+        let clients: Vec<_> = (0..3).map(|_| PayloadRecorder::default().start()).collect();
+        let room = RoomActor::new(service.recipient(), Arc::default()).start();
+
+        for (id, client) in (1..).zip(&clients) {
+            room.do_send(MessageFromClient::Connect(
+                ClientId::from(id),
+                client.clone().recipient(),
+                client.clone().recipient(),
+                ConnectionMetadata::default(),
+            ));
+            room.do_send(AuthorizationResult {
+                client: ClientId::from(id),
+                result: Ok(()),
+            });
+        }
+
+        let payload: Vec<u8> = (0..=255).cycle().take(100_000).collect();
+        room.send(MessageFromServer::new_binary(
+            MessageRecipient::Broadcast,
+            payload.clone(),
+        ))
+        .await
+        .unwrap();
+
+        let mut addresses = Vec::new();
+        for client in &clients {
+            match client.send(TakePayloads).await.unwrap().as_slice() {
+                [MessageData::Binary(received)] => {
+                    assert_eq!(payload, *received);
+                    addresses.push(received.as_ptr());
+                }
+                other => panic!("Unexpected payloads: {:?}", other),
+            }
+        }
+
+        // Every client received the same copy of the payload.
+        assert!(addresses.windows(2).all(|pair| pair[0] == pair[1]));
+    }
+
+    #[actix::test]
+    async fn test_message_schema() {
+        let service = Recorder::default().start();
+        let client = Recorder::default().start();
+        let room = RoomActor::new(service.clone().recipient(), Arc::default())
+            .with_message_schema(MessageSchema::new("type", ["move", "chat"]))
+            .start();
+
+        connect(&room, 1.into(), &client).await;
+        for message in [r#"{"type": "jump"}"#, r#"{"type": "move", "x": 1}"#] {
+            room.send(MessageFromClient::Message {
+                from_client: 1.into(),
+                data: MessageData::String(message.into()),
+            })
+            .await
+            .unwrap();
+        }
+
+        assert_eq!(
+            vec![
+                Event::Connect(1.into()),
+                Event::FromClient(1.into(), r#"{"type": "move", "x": 1}"#.to_string()),
+            ],
+            service.send(crate::test_util::TakeEvents).await.unwrap()
+        );
+        assert_eq!(
+            vec![Event::FromServer(
+                ClientId::from(1).into(),
+                r#"{"error":"Message type \"jump\" is not accepted."}"#.to_string()
+            )],
+            client.send(crate::test_util::TakeEvents).await.unwrap()
+        );
+    }
+
+    #[actix::test]
+    async fn test_messages_from_unaccepted_clients_dropped() {
+        let service = Recorder::default().start();
+        let client = Recorder::default().start();
+        let room = RoomActor::new(service.clone().recipient(), Arc::default()).start();
+        let hello = |from_client: ClientId| MessageFromClient::Message {
+            from_client,
+            data: MessageData::String("hello".into()),
+        };
+
+        // Client 1 sends a message while the service decides whether to accept it.
+        room.do_send(MessageFromClient::Connect(
+            1.into(),
+            client.clone().recipient(),
+            client.clone().recipient(),
+            ConnectionMetadata::default(),
+        ));
+        room.send(hello(1.into())).await.unwrap();
+        room.send(AuthorizationResult {
+            client: 1.into(),
+            result: Err("no".to_string()),
+        })
+        .await
+        .unwrap();
+        room.send(hello(1.into())).await.unwrap();
+
+        // Client 2 sends a message after it is kicked.
+        connect(&room, 2.into(), &client).await;
+        room.send(KickClient {
+            client: 2.into(),
+            reason: DisconnectReason::ServerKick,
+        })
+        .await
+        .unwrap();
+        room.send(hello(2.into())).await.unwrap();
+
+        assert_eq!(
+            vec![
+                Event::Connect(1.into()),
+                Event::Connect(2.into()),
+                Event::Disconnect(2.into(), DisconnectReason::ServerKick),
+            ],
+            service.send(crate::test_util::TakeEvents).await.unwrap()
+        );
+    }
+
+    #[actix::test]
+    async fn test_unsupported_message_closes_awaiting_client() {
+        let service = Recorder::default().start();
+        let client = Recorder::default().start();
+        let room = RoomActor::new(service.clone().recipient(), Arc::default())
+            .with_accepted_messages(AcceptedMessages::TEXT_ONLY)
+            .with_close_on_unsupported_message(true)
+            .start();
+
+        room.do_send(MessageFromClient::Connect(
+            1.into(),
+            client.clone().recipient(),
+            client.clone().recipient(),
+            ConnectionMetadata::default(),
+        ));
+        room.send(MessageFromClient::Message {
+            from_client: 1.into(),
+            data: MessageData::Binary(vec![1].into()),
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            vec![Event::Close(DisconnectReason::ServerKick)],
+            client.send(crate::test_util::TakeEvents).await.unwrap()
+        );
+
+        // The service accepts the client after it was closed, so it is told
+        // that the client left.
+        room.send(AuthorizationResult {
+            client: 1.into(),
+            result: Ok(()),
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            vec![
+                Event::Connect(1.into()),
+                Event::Disconnect(1.into(), DisconnectReason::ServerKick),
+            ],
+            service.send(crate::test_util::TakeEvents).await.unwrap()
+        );
+    }
+
+    #[actix::test]
+    async fn test_close_on_unsupported_message() {
+        let service = Recorder::default().start();
+        let client = Recorder::default().start();
+        let room = RoomActor::new(service.clone().recipient(), Arc::default())
+            .with_accepted_messages(AcceptedMessages::TEXT_ONLY)
+            .with_close_on_unsupported_message(true)
+            .start();
+
+        connect(&room, 1.into(), &client).await;
+        room.send(MessageFromClient::Message {
+            from_client: 1.into(),
+            data: MessageData::Binary(vec![1, 2, 3].into()),
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                Event::Connect(1.into()),
+                Event::Disconnect(1.into(), DisconnectReason::ServerKick),
+            ],
+            service.send(crate::test_util::TakeEvents).await.unwrap()
+        );
+        assert_eq!(
+            vec![Event::Close(DisconnectReason::ServerKick)],
+            client.send(crate::test_util::TakeEvents).await.unwrap()
+        );
+    }
+
+    #[actix::test]
+    async fn test_buffer_until_connected() {
+        for buffer in [true, false] {
+            let service = Recorder::default().start();
+            let client = Recorder::default().start();
+            let room = RoomActor::new(service.recipient(), Arc::default())
+                .with_buffer_until_connected(buffer)
+                .start();
+
+            let client_id = room.send(AssignClientId { token: None }).await.unwrap();
+
+            // The service sends a welcome message before the room has seen the connection.
+            for message in ["welcome", "to the room"] {
+                room.do_send(MessageFromServer::new(
+                    client_id.into(),
+                    message.to_string(),
+                ));
+            }
+            connect(&room, client_id, &client).await;
+
+            let expected = if buffer {
+                vec![
+                    Event::FromServer(client_id.into(), "welcome".to_string()),
+                    Event::FromServer(client_id.into(), "to the room".to_string()),
+                ]
+            } else {
+                vec![]
+            };
+            assert_eq!(
+                expected,
+                client.send(crate::test_util::TakeEvents).await.unwrap()
+            );
+        }
+    }
+
+    #[actix::test]
+    async fn test_authorization() {
+        let service = Recorder::default().start();
+        let accepted = Recorder::default().start();
+        let rejected = Recorder::default().start();
+        let room = RoomActor::new(service.clone().recipient(), Arc::default()).start();
+
+        for (id, client) in [(1, &accepted), (2, &rejected)] {
+            room.do_send(MessageFromClient::Connect(
+                id.into(),
+                client.clone().recipient(),
+                client.clone().recipient(),
+                ConnectionMetadata::default(),
+            ));
+        }
+
+        // Messages sent while the service decides are held back.
+        room.do_send(MessageFromServer::new(
+            MessageRecipient::Broadcast,
+            "secret".to_string(),
+        ));
+        room.send(AuthorizationResult {
+            client: 2.into(),
+            result: Err("Invalid invite".to_string()),
+        })
+        .await
+        .unwrap();
+        assert!(accepted
+            .send(crate::test_util::TakeEvents)
+            .await
+            .unwrap()
+            .is_empty());
+
+        room.send(AuthorizationResult {
+            client: 1.into(),
+            result: Ok(()),
+        })
+        .await
+        .unwrap();
+        room.send(MessageFromServer::new(
+            MessageRecipient::Broadcast,
+            "hello".to_string(),
+        ))
+        .await
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                Event::FromServer(MessageRecipient::Broadcast, "secret".to_string()),
+                Event::FromServer(MessageRecipient::Broadcast, "hello".to_string()),
+            ],
+            accepted.send(crate::test_util::TakeEvents).await.unwrap()
+        );
+        assert_eq!(
+            vec![Event::Close(DisconnectReason::Rejected)],
+            rejected.send(crate::test_util::TakeEvents).await.unwrap()
+        );
+
+        // The rejected client's own disconnection is not passed on to the service.
+        room.send(MessageFromClient::Disconnect(
+            2.into(),
+            DisconnectReason::ClientClosed,
+        ))
+        .await
+        .unwrap();
+        assert_eq!(
+            vec![Event::Connect(1.into()), Event::Connect(2.into())],
+            service.send(crate::test_util::TakeEvents).await.unwrap()
+        );
+    }
+
+    #[actix::test]
+    async fn test_disconnect_before_authorization() {
+        let service = Recorder::default().start();
+        let client = Recorder::default().start();
+        let room = RoomActor::new(service.clone().recipient(), Arc::default()).start();
+
+        for id in [1, 2] {
+            room.do_send(MessageFromClient::Connect(
+                id.into(),
+                client.clone().recipient(),
+                client.clone().recipient(),
+                ConnectionMetadata::default(),
+            ));
+            room.do_send(MessageFromClient::Disconnect(
+                id.into(),
+                DisconnectReason::ClientClosed,
+            ));
+        }
+
+        room.do_send(AuthorizationResult {
+            client: 1.into(),
+            result: Ok(()),
+        });
+        room.send(AuthorizationResult {
+            client: 2.into(),
+            result: Err("Invalid invite".to_string()),
+        })
+        .await
+        .unwrap();
+
+        // Only the accepted client's disconnection is passed on to the service.
+        assert_eq!(
+            vec![
+                Event::Connect(1.into()),
+                Event::Connect(2.into()),
+                Event::Disconnect(1.into(), DisconnectReason::ClientClosed),
+            ],
+            service.send(crate::test_util::TakeEvents).await.unwrap()
+        );
+        assert!(client
+            .send(crate::test_util::TakeEvents)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
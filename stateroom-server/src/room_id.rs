@@ -0,0 +1,272 @@
+use rand::Rng;
+use std::{error::Error, fmt::Display, sync::Arc};
+
+/// Maps the key a room is routed by to the room ID passed to its service, for
+/// example to add or strip a tenant prefix.
+pub type RoomIdTransform = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+/// Produces identifiers for new rooms.
+pub trait RoomIdGenerator: Send + Sync {
+    fn generate(&self) -> String;
+}
+
+const ADJECTIVES: &[&str] = &[
+    "amber", "bold", "brave", "bright", "calm", "clever", "cosmic", "crisp", "curious", "daring",
+    "dusty", "eager", "early", "fancy", "fast", "fierce", "fluffy", "friendly", "gentle", "giant",
+    "golden", "grand", "happy", "hidden", "humble", "icy", "jolly", "keen", "kind", "lively",
+    "lucky", "mellow", "merry", "mighty", "misty", "noble", "odd", "plucky", "polite", "proud",
+    "quick", "quiet", "rapid", "rosy", "rusty", "shiny", "silent", "silver", "sleepy", "sly",
+    "smooth", "snowy", "sunny", "swift", "tidy", "tiny", "vast", "velvet", "vivid", "warm", "wild",
+    "wise", "witty", "zesty",
+];
+
+const NOUNS: &[&str] = &[
+    "badger", "beacon", "bison", "canyon", "cedar", "comet", "coral", "crane", "creek", "falcon",
+    "fern", "finch", "fjord", "forest", "fox", "garden", "gecko", "geyser", "glacier", "harbor",
+    "hawk", "heron", "island", "jaguar", "koala", "lagoon", "lantern", "lemur", "lynx", "maple",
+    "meadow", "meteor", "moose", "nebula", "newt", "oasis", "ocean", "orchid", "otter", "owl",
+    "panda", "pebble", "pine", "planet", "prairie", "quartz", "raven", "reef", "river", "robin",
+    "salmon", "sparrow", "summit", "tiger", "tulip", "tundra", "valley", "violet", "walrus",
+    "willow", "wombat", "yak", "zebra", "zephyr",
+];
+
+/// Generates memorable room IDs like `brave-otter-42`: a sequence of words
+/// followed by a number from 0 to 999.
+///
+/// The last word is a noun and the words before it are adjectives. With the
+/// default of two words there are about four million possible IDs, so
+/// collisions are unlikely but possible; callers that require uniqueness
+/// should check for existing rooms.
+#[derive(Clone, Debug)]
+pub struct WordsRoomIdGenerator {
+    words: usize,
+}
+
+impl WordsRoomIdGenerator {
+    /// Create a generator that uses `words` words per ID (at least one).
+    #[must_use]
+    pub fn new(words: usize) -> Self {
+        WordsRoomIdGenerator {
+            words: words.max(1),
+        }
+    }
+}
+
+impl Default for WordsRoomIdGenerator {
+    fn default() -> Self {
+        WordsRoomIdGenerator::new(2)
+    }
+}
+
+impl RoomIdGenerator for WordsRoomIdGenerator {
+    fn generate(&self) -> String {
+        let mut rng = rand::thread_rng();
+        let mut parts: Vec<String> = (1..self.words)
+            .map(|_| ADJECTIVES[rng.gen_range(0..ADJECTIVES.len())].to_string())
+            .collect();
+        parts.push(NOUNS[rng.gen_range(0..NOUNS.len())].to_string());
+        parts.push(rng.gen_range(0..1000).to_string());
+
+        parts.join("-")
+    }
+}
+
+/// Rules that room IDs must follow. See [validate_room_id].
+#[derive(Clone, Debug)]
+pub struct RoomIdPolicy {
+    /// The maximum length of an ID, in characters. Defaults to 64.
+    pub max_len: usize,
+
+    /// IDs that may not be used, such as those that would collide with the
+    /// server's own routes. Compared after normalization. Defaults to
+    /// `client`, `metrics`, `stats`, `status` and `ws`.
+    pub reserved: Vec<String>,
+
+    /// Whether IDs are converted to lowercase, so that IDs differing only in
+    /// case refer to the same room. Defaults to true.
+    pub lowercase: bool,
+}
+
+impl Default for RoomIdPolicy {
+    fn default() -> Self {
+        RoomIdPolicy {
+            max_len: 64,
+            reserved: ["client", "metrics", "stats", "status", "ws"]
+                .iter()
+                .map(|name| (*name).to_string())
+                .collect(),
+            lowercase: true,
+        }
+    }
+}
+
+/// The reason a room ID was rejected by [validate_room_id].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RoomIdError {
+    Empty,
+    TooLong { max_len: usize },
+    InvalidCharacter(char),
+    Reserved(String),
+}
+
+impl Display for RoomIdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "Room ID is empty."),
+            Self::TooLong { max_len } => {
+                write!(f, "Room ID is longer than {} characters.", max_len)
+            }
+            Self::InvalidCharacter(c) => write!(f, "Room ID contains invalid character {:?}.", c),
+            Self::Reserved(id) => write!(f, "Room ID {:?} is reserved.", id),
+        }
+    }
+}
+
+impl Error for RoomIdError {}
+
+/// Normalize `room_id` and check it against `policy`, returning the normalized
+/// ID.
+///
+/// Surrounding whitespace is removed and, if the policy says so, the ID is
+/// converted to lowercase. The result must be non-empty, at most
+/// `policy.max_len` characters long, consist only of ASCII letters, digits,
+/// `-` and `_`, and not be one of `policy.reserved`.
+pub fn validate_room_id(room_id: &str, policy: &RoomIdPolicy) -> Result<String, RoomIdError> {
+    let room_id = room_id.trim();
+    let room_id = if policy.lowercase {
+        room_id.to_lowercase()
+    } else {
+        room_id.to_string()
+    };
+
+    if room_id.is_empty() {
+        return Err(RoomIdError::Empty);
+    }
+
+    if let Some(c) = room_id
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || *c == '-' || *c == '_'))
+    {
+        return Err(RoomIdError::InvalidCharacter(c));
+    }
+
+    // Only ASCII remains, so the length in bytes is the length in characters.
+    if room_id.len() > policy.max_len {
+        return Err(RoomIdError::TooLong {
+            max_len: policy.max_len,
+        });
+    }
+
+    if policy.reserved.iter().any(|reserved| *reserved == room_id) {
+        return Err(RoomIdError::Reserved(room_id));
+    }
+
+    Ok(room_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn assert_format(id: &str, words: usize) {
+        let parts: Vec<&str> = id.split('-').collect();
+        assert_eq!(words + 1, parts.len(), "Unexpected format: {}", id);
+
+        for adjective in &parts[..words - 1] {
+            assert!(ADJECTIVES.contains(adjective), "Unexpected format: {}", id);
+        }
+        assert!(
+            NOUNS.contains(&parts[words - 1]),
+            "Unexpected format: {}",
+            id
+        );
+
+        let number: u32 = parts[words].parse().unwrap();
+        assert!(number < 1000);
+    }
+
+    #[test]
+    fn test_words_format() {
+        for words in 1..=4 {
+            let generator = WordsRoomIdGenerator::new(words);
+            for _ in 0..100 {
+                assert_format(&generator.generate(), words);
+            }
+        }
+
+        // Zero words is treated as one.
+        assert_format(&WordsRoomIdGenerator::new(0).generate(), 1);
+    }
+
+    #[test]
+    fn test_words_do_not_collide() {
+        // With four words there are about 17 billion possible IDs, so the
+        // chance of any collision among 10,000 of them is about 0.3%.
+        let generator = WordsRoomIdGenerator::new(4);
+        let ids: HashSet<String> = (0..10_000).map(|_| generator.generate()).collect();
+
+        assert_eq!(10_000, ids.len());
+    }
+
+    #[test]
+    fn test_wordlists_are_unique() {
+        assert_eq!(
+            ADJECTIVES.len(),
+            ADJECTIVES.iter().collect::<HashSet<_>>().len()
+        );
+        assert_eq!(NOUNS.len(), NOUNS.iter().collect::<HashSet<_>>().len());
+    }
+
+    #[test]
+    fn test_validate_room_id() {
+        let policy = RoomIdPolicy::default();
+
+        assert_eq!(
+            Ok("lobby_2".to_string()),
+            validate_room_id("lobby_2", &policy)
+        );
+        assert_eq!(
+            Ok("brave-otter-42".to_string()),
+            validate_room_id(" Brave-Otter-42\n", &policy)
+        );
+        assert_eq!(Err(RoomIdError::Empty), validate_room_id("  ", &policy));
+        assert_eq!(
+            Err(RoomIdError::InvalidCharacter('/')),
+            validate_room_id("a/b", &policy)
+        );
+        assert_eq!(
+            Err(RoomIdError::InvalidCharacter('é')),
+            validate_room_id("café", &policy)
+        );
+        assert_eq!(
+            Err(RoomIdError::TooLong { max_len: 64 }),
+            validate_room_id(&"a".repeat(65), &policy)
+        );
+        assert!(validate_room_id(&"a".repeat(64), &policy).is_ok());
+        assert_eq!(
+            Err(RoomIdError::Reserved("metrics".to_string())),
+            validate_room_id("Metrics", &policy)
+        );
+        assert_eq!(
+            Err(RoomIdError::Reserved("stats".to_string())),
+            validate_room_id("stats", &policy)
+        );
+
+        let case_sensitive = RoomIdPolicy {
+            lowercase: false,
+            ..RoomIdPolicy::default()
+        };
+        assert_eq!(
+            Ok("Metrics".to_string()),
+            validate_room_id("Metrics", &case_sensitive)
+        );
+
+        // Generated IDs are always valid.
+        let generator = WordsRoomIdGenerator::default();
+        for _ in 0..100 {
+            let id = generator.generate();
+            assert_eq!(Ok(id.clone()), validate_room_id(&id, &policy));
+        }
+    }
+}
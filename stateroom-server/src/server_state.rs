@@ -1,58 +1,118 @@
+use crate::messages::ReloadService;
+use crate::metrics::Metrics;
 use crate::service_actor::{ServiceActor, ServiceActorContext};
-use crate::{RoomActor, Server};
+use crate::stats::MessageRate;
+use crate::{validate_room_id, RoomActor, Server, SlowStartLimiter};
 use actix::dev::channel::channel;
-use actix::{Addr, Arbiter, Context};
-use actix_web::Result;
+use actix::{Addr, Arbiter, Context, Recipient};
 use stateroom::{StateroomService, StateroomServiceFactory};
+use std::{sync::Arc, time::Instant};
 
 const MAILBOX_SIZE: usize = 16;
 
 pub struct ServerState {
     pub room_addr: Addr<RoomActor>,
+    pub reload_recipient: Recipient<ReloadService>,
     pub settings: Server,
+    pub metrics: Arc<Metrics>,
+    pub slow_start: Option<SlowStartLimiter>,
+    pub started: Instant,
+    pub message_rate: MessageRate,
 }
 
 impl ServerState {
+    /// Start the room and service actors, returning an error if the service
+    /// could not be built or the transformed room ID is invalid (see
+    /// [Server::room_id_policy]).
     pub fn new<J>(
         service_factory: impl StateroomServiceFactory<ServiceActorContext, Service = J> + Send + 'static,
         settings: Server,
-    ) -> Result<Self>
+    ) -> std::io::Result<Self>
     where
         J: StateroomService + Send + Sync + Unpin + 'static,
     {
+        let room_id = match &settings.room_id_transform {
+            Some(transform) => validate_room_id(&transform(""), &settings.room_id_policy)
+                .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidInput, error))?,
+            None => String::new(),
+        };
+
         let arbiter = Arbiter::new();
         let (room_tx, room_rx) = channel(MAILBOX_SIZE);
         let (service_tx, service_rx) = channel(MAILBOX_SIZE);
         let room_addr = Addr::new(room_tx);
         let service_addr = Addr::new(service_tx);
+        let reload_recipient = service_addr.clone().recipient();
+        let metrics = Arc::new(Metrics::new(&settings.payload_size_buckets));
+        let buffer_until_connected = settings.buffer_until_connected;
+        let message_schema = settings.message_schema.clone();
+        let close_on_unsupported_message = settings.close_on_unsupported_message;
+        let (started_tx, started_rx) = std::sync::mpsc::sync_channel(1);
 
         {
             let room_addr = room_addr.clone();
+            let metrics = metrics.clone();
 
             arbiter.spawn_fn(move || {
                 let room_ctx = Context::with_receiver(room_rx);
                 let service_ctx = Context::with_receiver(service_rx);
 
-                let service_actor = ServiceActor::<J>::new(
+                let service_actor = match ServiceActor::<J>::new_with_room_id(
                     &service_ctx,
+                    &room_id,
                     service_factory,
                     room_addr.clone().recipient(),
-                );
+                    room_addr.clone().recipient(),
+                ) {
+                    Ok(service_actor) => service_actor
+                        .with_accepted_messages_recipient(room_addr.clone().recipient()),
+                    Err(error) => {
+                        tracing::error!(?error, "Could not create service actor for room");
+                        let _ = started_tx.send(Err(error));
+                        return;
+                    }
+                };
 
-                let room_actor = RoomActor::new(service_addr.recipient());
+                let mut room_actor = RoomActor::new(service_addr.recipient(), metrics)
+                    .with_buffer_until_connected(buffer_until_connected)
+                    .with_accepted_messages(service_actor.accepted_messages())
+                    .with_close_on_unsupported_message(close_on_unsupported_message);
+                if let Some(message_schema) = message_schema {
+                    room_actor = room_actor.with_message_schema(message_schema);
+                }
 
                 room_ctx.run(room_actor);
-                if let Some(service_actor) = service_actor {
-                    service_ctx.run(service_actor);
-                } else {
-                    tracing::error!("Could not create service actor for room");
-                }
+                service_ctx.run(service_actor);
+                let _ = started_tx.send(Ok(()));
             });
         }
 
+        let started = started_rx.recv().unwrap_or_else(|_| {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "Room arbiter stopped before the service started.",
+            ))
+        });
+
+        if let Err(error) = started {
+            arbiter.stop();
+            return Err(error);
+        }
+
+        let started = Instant::now();
+        let slow_start = settings
+            .slow_start
+            .clone()
+            .map(|policy| SlowStartLimiter::new(policy, started));
+
         Ok(ServerState {
             settings,
             room_addr,
+            reload_recipient,
+            metrics,
+            slow_start,
+            started,
+            message_rate: MessageRate::new(started),
         })
     }
 }
@@ -1,11 +1,35 @@
-use crate::messages::{MessageData, MessageFromClient, MessageFromServer};
+use crate::messages::{
+    AuthorizationResult, MessageBatch, MessageData, MessageFromClient, MessageFromServer,
+    ReloadService, SetAcceptedMessages,
+};
 use actix::{Actor, AsyncContext, Context, Handler, Message, Recipient, SpawnHandle};
-use stateroom::{MessageRecipient, StateroomContext, StateroomService, StateroomServiceFactory};
-use std::time::Duration;
+use stateroom::{
+    AcceptedMessages, ClientId, ConnectionMetadata, MessageRecipient, StateroomContext,
+    StateroomService, StateroomServiceFactory,
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Refreshes the factory a service was built from and builds a new one.
+type Rebuild<J> = Box<dyn Fn(&ServiceActorContext) -> std::io::Result<J>>;
 
 pub struct ServiceActor<J: StateroomService + Send + Sync + 'static> {
     service: J,
+    rebuild: Rebuild<J>,
+    /// Clients the service has accepted and the metadata they connected with,
+    /// replayed to a rebuilt service that cannot restore a snapshot.
+    clients: BTreeMap<ClientId, ConnectionMetadata>,
+    context: ServiceActorContext,
     timer_handle: Option<SpawnHandle>,
+    authorization_recipient: Recipient<AuthorizationResult>,
+    /// The types of message the service accepted when it was last asked.
+    accepted_messages: AcceptedMessages,
+    /// Told when the types of message the service accepts change.
+    accepted_messages_recipient: Option<Recipient<SetAcceptedMessages>>,
 }
 
 struct SetTimer(u32);
@@ -21,15 +45,41 @@ impl Message for TimerFinished {
 
 /// A [StateroomContext] implementation for [StateroomService]s hosted in the
 /// context of a [ServiceActor].
+///
+/// Messages sent while the [ServiceActor] is calling into the service (for
+/// example, from `timer` or `message`) are collected and passed to the room as
+/// one [MessageBatch] when the call returns. Messages sent outside of a call,
+/// such as from a service's own background thread, are passed on immediately.
 #[derive(Clone)]
 pub struct ServiceActorContext {
     set_timer_recipient: Recipient<SetTimer>,
-    send_message_recipient: Recipient<MessageFromServer>,
+    send_message_recipient: Recipient<MessageBatch>,
+    /// Messages sent during the current callback, or `None` between callbacks.
+    batch: Arc<Mutex<Option<Vec<MessageFromServer>>>>,
 }
 
 impl ServiceActorContext {
     fn try_send(&self, message: MessageFromServer) {
-        self.send_message_recipient.do_send(message);
+        if let Some(batch) = self.batch.lock().unwrap().as_mut() {
+            batch.push(message);
+            return;
+        }
+
+        self.send_message_recipient
+            .do_send(MessageBatch(vec![message]));
+    }
+
+    fn start_batch(&self) {
+        *self.batch.lock().unwrap() = Some(Vec::new());
+    }
+
+    fn flush_batch(&self) {
+        let batch = self.batch.lock().unwrap().take();
+        if let Some(messages) = batch {
+            if !messages.is_empty() {
+                self.send_message_recipient.do_send(MessageBatch(messages));
+            }
+        }
     }
 }
 
@@ -54,24 +104,104 @@ impl StateroomContext for ServiceActorContext {
 }
 
 impl<J: StateroomService + Send + Sync + 'static + Unpin> ServiceActor<J> {
-    #[must_use]
     pub fn new(
         ctx: &Context<Self>,
-        service_factory: impl StateroomServiceFactory<ServiceActorContext, Service = J>,
-        recipient: Recipient<MessageFromServer>,
-    ) -> Option<Self> {
+        service_factory: impl StateroomServiceFactory<ServiceActorContext, Service = J> + 'static,
+        recipient: Recipient<MessageBatch>,
+        authorization_recipient: Recipient<AuthorizationResult>,
+    ) -> std::io::Result<Self> {
+        Self::new_with_room_id(ctx, "", service_factory, recipient, authorization_recipient)
+    }
+
+    /// Like [ServiceActor::new], but builds the service with the given room ID.
+    pub fn new_with_room_id(
+        ctx: &Context<Self>,
+        room_id: &str,
+        service_factory: impl StateroomServiceFactory<ServiceActorContext, Service = J> + 'static,
+        recipient: Recipient<MessageBatch>,
+        authorization_recipient: Recipient<AuthorizationResult>,
+    ) -> std::io::Result<Self> {
         let host_context = ServiceActorContext {
             set_timer_recipient: ctx.address().recipient(),
             send_message_recipient: recipient,
+            batch: Arc::default(),
         };
 
-        let service = service_factory.build("", host_context).unwrap();
+        // Messages sent while the service is built are delivered together.
+        host_context.start_batch();
+        let service = service_factory.build(room_id, host_context.clone());
+        host_context.flush_batch();
+
+        let service = service.map_err(|error| service_error("Could not build service", error))?;
 
-        Some(ServiceActor {
+        let room_id = room_id.to_string();
+        let rebuild: Rebuild<J> = Box::new(move |context: &ServiceActorContext| {
+            service_factory
+                .refresh()
+                .map_err(|error| service_error("Could not refresh service factory", error))?;
+            service_factory
+                .build(&room_id, context.clone())
+                .map_err(|error| service_error("Could not build service", error))
+        });
+
+        Ok(ServiceActor {
+            accepted_messages: service.accepted_messages(),
             service,
+            rebuild,
+            clients: BTreeMap::new(),
+            context: host_context,
             timer_handle: None,
+            authorization_recipient,
+            accepted_messages_recipient: None,
         })
     }
+
+    /// The types of message the service accepts from clients.
+    pub fn accepted_messages(&self) -> AcceptedMessages {
+        self.service.accepted_messages()
+    }
+
+    /// Tell `recipient` whenever the types of message the service accepts
+    /// change, as they can for a service that learns them from a process it
+    /// restarts. Changes are noticed after each call into the service.
+    #[must_use]
+    pub fn with_accepted_messages_recipient(
+        mut self,
+        recipient: Recipient<SetAcceptedMessages>,
+    ) -> Self {
+        self.accepted_messages_recipient = Some(recipient);
+        self
+    }
+
+    /// Calls into the service, delivering the messages it sends during the call
+    /// as a single [MessageBatch].
+    fn batched<T>(&mut self, callback: impl FnOnce(&mut J) -> T) -> T {
+        self.context.start_batch();
+        let result = callback(&mut self.service);
+        self.context.flush_batch();
+        self.check_accepted_messages();
+
+        result
+    }
+
+    /// Tells the accepted messages recipient if the types of message the
+    /// service accepts have changed.
+    fn check_accepted_messages(&mut self) {
+        let accepted = self.service.accepted_messages();
+        if accepted != self.accepted_messages {
+            self.accepted_messages = accepted;
+            if let Some(recipient) = &self.accepted_messages_recipient {
+                recipient.do_send(SetAcceptedMessages(accepted));
+            }
+        }
+    }
+}
+
+fn service_error(description: &str, error: impl Debug) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Other,
+        format!("{}: {:?}", description, error),
+    )
 }
 
 impl<J: StateroomService + Send + Sync + 'static + Unpin> Actor for ServiceActor<J> {
@@ -83,20 +213,32 @@ impl<J: StateroomService + Send + Sync + 'static + Unpin> Actor for ServiceActor
     }
 }
 
-impl<J: StateroomService + Send + Sync + 'static + Unpin> Handler<MessageFromClient> for ServiceActor<J> {
+impl<J: StateroomService + Send + Sync + 'static + Unpin> Handler<MessageFromClient>
+    for ServiceActor<J>
+{
     type Result = ();
 
     fn handle(&mut self, msg: MessageFromClient, _ctx: &mut Self::Context) -> Self::Result {
         match msg {
-            MessageFromClient::Connect(u, _) => {
-                self.service.connect(u);
+            MessageFromClient::Connect(u, _, _, metadata) => {
+                let result = self.batched(|service| service.connect_with_metadata(u, &metadata));
+                if result.is_ok() {
+                    self.clients.insert(u, metadata);
+                }
+                self.authorization_recipient
+                    .do_send(AuthorizationResult { client: u, result });
             }
-            MessageFromClient::Disconnect(u) => {
-                self.service.disconnect(u);
+            MessageFromClient::Disconnect(u, reason) => {
+                self.clients.remove(&u);
+                self.batched(|service| service.disconnect_with_reason(u, reason));
             }
             MessageFromClient::Message { data, from_client } => match data {
-                MessageData::Binary(bin) => self.service.binary(from_client, &bin),
-                MessageData::String(st) => self.service.message(from_client, &st),
+                MessageData::Binary(bin) => {
+                    self.batched(|service| service.binary(from_client, &bin))
+                }
+                MessageData::String(st) => {
+                    self.batched(|service| service.message(from_client, &st))
+                }
             },
         }
     }
@@ -120,11 +262,258 @@ impl<J: StateroomService + Send + Sync + 'static + Unpin> Handler<SetTimer> for
     }
 }
 
-impl<J: StateroomService + Send + Sync + 'static + Unpin> Handler<TimerFinished> for ServiceActor<J> {
+/// The rebuilt service takes over the current service's state if the current
+/// service can take a [snapshot](StateroomService::snapshot). Otherwise, it
+/// starts afresh and is sent a `connect` for each client that is connected.
+impl<J: StateroomService + Send + Sync + 'static + Unpin> Handler<ReloadService>
+    for ServiceActor<J>
+{
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, _: ReloadService, _: &mut Self::Context) -> Self::Result {
+        let snapshot = self.service.snapshot();
+
+        // Messages sent while the service is rebuilt are delivered together.
+        self.context.start_batch();
+        let result = (self.rebuild)(&self.context).and_then(|mut service| {
+            if let Some(snapshot) = &snapshot {
+                service
+                    .restore(snapshot)
+                    .map_err(|error| service_error("Could not restore service", error))?;
+            } else {
+                for (client, metadata) in &self.clients {
+                    if let Err(error) = service.connect_with_metadata(*client, metadata) {
+                        tracing::warn!(?client, %error, "Rebuilt service rejected client");
+                    }
+                }
+            }
+            Ok(service)
+        });
+        self.context.flush_batch();
+
+        match result {
+            Ok(service) => {
+                tracing::info!("Reloaded service");
+                self.service = service;
+                self.check_accepted_messages();
+                Ok(())
+            }
+            Err(error) => {
+                tracing::error!(?error, "Could not reload service");
+                Err(error.to_string())
+            }
+        }
+    }
+}
+
+impl<J: StateroomService + Send + Sync + 'static + Unpin> Handler<TimerFinished>
+    for ServiceActor<J>
+{
     type Result = ();
 
     fn handle(&mut self, _: TimerFinished, _: &mut Self::Context) -> Self::Result {
         tracing::info!("Timer finished.");
-        self.service.timer();
+        self.batched(|service| service.timer());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::room_actor::RoomActor;
+    use crate::test_util::{Event, Recorder, TakeEvents};
+    use stateroom::{ClientId, ConnectionMetadata, SimpleStateroomService};
+
+    /// Sends a round of updates on each tick.
+    #[derive(Clone)]
+    struct Ticker;
+
+    impl SimpleStateroomService for Ticker {
+        fn new(_: &str, _: &impl StateroomContext) -> Self {
+            Ticker
+        }
+
+        fn timer(&mut self, context: &impl StateroomContext) {
+            context.send_message(MessageRecipient::Broadcast, "tick");
+            context.send_message(ClientId::from(1), "you are winning");
+            context.send_message(MessageRecipient::Broadcast, "tock");
+        }
+    }
+
+    #[actix::test]
+    async fn test_timer_messages_delivered_as_batch() {
+        let service_ctx = Context::new();
+        let room = Recorder::default().start();
+        let service = ServiceActor::new(
+            &service_ctx,
+            Ticker,
+            room.clone().recipient(),
+            room.clone().recipient(),
+        )
+        .unwrap();
+        let service = service_ctx.run(service);
+
+        service.send(TimerFinished).await.unwrap();
+
+        // The room is sent the whole tick at once, in the order it was sent.
+        let broadcast =
+            |text: &str| Event::FromServer(MessageRecipient::Broadcast, text.to_string());
+        assert_eq!(
+            vec![Event::Batch(vec![
+                broadcast("tick"),
+                Event::FromServer(ClientId::from(1).into(), "you are winning".to_string()),
+                broadcast("tock"),
+            ])],
+            room.send(TakeEvents).await.unwrap()
+        );
+    }
+
+    /// Accepts binary messages only after a client asks it to.
+    #[derive(Clone)]
+    struct Switchable {
+        binary: bool,
+    }
+
+    impl SimpleStateroomService for Switchable {
+        fn new(_: &str, _: &impl StateroomContext) -> Self {
+            Switchable { binary: false }
+        }
+
+        fn message(&mut self, _: ClientId, message: &str, _: &impl StateroomContext) {
+            self.binary = message == "binary";
+        }
+
+        fn binary(&mut self, _: ClientId, message: &[u8], context: &impl StateroomContext) {
+            context.send_binary(MessageRecipient::Broadcast, message);
+        }
+
+        fn accepted_messages(&self) -> AcceptedMessages {
+            if self.binary {
+                AcceptedMessages::ALL
+            } else {
+                AcceptedMessages::TEXT_ONLY
+            }
+        }
+    }
+
+    #[actix::test]
+    async fn test_accepted_messages_refreshed() {
+        let service_ctx = Context::new();
+        let room = RoomActor::new(service_ctx.address().recipient(), Arc::default())
+            .with_accepted_messages(AcceptedMessages::TEXT_ONLY)
+            .start();
+        let service = ServiceActor::new(
+            &service_ctx,
+            Switchable { binary: false },
+            room.clone().recipient(),
+            room.clone().recipient(),
+        )
+        .unwrap()
+        .with_accepted_messages_recipient(room.clone().recipient());
+        service_ctx.run(service);
+
+        let client = Recorder::default().start();
+        room.do_send(MessageFromClient::Connect(
+            ClientId::from(1),
+            client.clone().recipient(),
+            client.clone().recipient(),
+            ConnectionMetadata::default(),
+        ));
+        actix::clock::sleep(Duration::from_millis(50)).await;
+        let send_binary = || {
+            room.do_send(MessageFromClient::Message {
+                from_client: ClientId::from(1),
+                data: MessageData::Binary(vec![1].into()),
+            })
+        };
+
+        send_binary();
+        room.do_send(MessageFromClient::Message {
+            from_client: ClientId::from(1),
+            data: MessageData::String("binary".into()),
+        });
+        actix::clock::sleep(Duration::from_millis(50)).await;
+        send_binary();
+        actix::clock::sleep(Duration::from_millis(50)).await;
+
+        // Only the binary message sent after the service began accepting them
+        // reaches it.
+        assert_eq!(
+            vec![Event::FromServer(
+                MessageRecipient::Broadcast,
+                "[1]".to_string()
+            )],
+            client.send(TakeEvents).await.unwrap()
+        );
+    }
+
+    /// Counts messages, broadcasting the count after each one.
+    #[derive(Clone)]
+    struct Counter {
+        count: u8,
+    }
+
+    impl SimpleStateroomService for Counter {
+        fn new(_: &str, _: &impl StateroomContext) -> Self {
+            Counter { count: 0 }
+        }
+
+        fn message(&mut self, _: ClientId, _: &str, context: &impl StateroomContext) {
+            self.count += 1;
+            context.send_message(MessageRecipient::Broadcast, &self.count.to_string());
+        }
+
+        fn snapshot(&self) -> Option<Vec<u8>> {
+            Some(vec![self.count])
+        }
+
+        fn restore(&mut self, snapshot: &[u8], _: &impl StateroomContext) -> Result<(), String> {
+            self.count = snapshot[0];
+            Ok(())
+        }
+    }
+
+    #[actix::test]
+    async fn test_reload_keeps_snapshot_state() {
+        let service_ctx = Context::new();
+        let room = RoomActor::new(service_ctx.address().recipient(), Arc::default()).start();
+        let service = ServiceActor::new(
+            &service_ctx,
+            Counter { count: 0 },
+            room.clone().recipient(),
+            room.clone().recipient(),
+        )
+        .unwrap();
+        let service = service_ctx.run(service);
+
+        let client = Recorder::default().start();
+        room.do_send(MessageFromClient::Connect(
+            ClientId::from(1),
+            client.clone().recipient(),
+            client.clone().recipient(),
+            ConnectionMetadata::default(),
+        ));
+        let count = || {
+            service.send(MessageFromClient::Message {
+                from_client: ClientId::from(1),
+                data: MessageData::String("count".into()),
+            })
+        };
+
+        count().await.unwrap();
+        service.send(ReloadService).await.unwrap().unwrap();
+        count().await.unwrap();
+        room.send(crate::room_actor::GetConnectionInfo)
+            .await
+            .unwrap();
+
+        // The rebuilt service carries on from the old one's count, and is not
+        // sent the client's connect again.
+        let broadcast =
+            |text: &str| Event::FromServer(MessageRecipient::Broadcast, text.to_string());
+        assert_eq!(
+            vec![broadcast("1"), broadcast("2")],
+            client.send(TakeEvents).await.unwrap()
+        );
     }
 }
@@ -0,0 +1,120 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Limits the rate at which a server accepts new connections for a while
+/// after it starts, so that a burst of clients reconnecting at once does not
+/// overwhelm the service.
+///
+/// The limit rises linearly from `initial_rate` to `final_rate` over
+/// `ramp_duration`, after which connections are no longer limited.
+#[derive(Clone, Debug)]
+pub struct SlowStart {
+    /// Connections per second accepted immediately after startup.
+    pub initial_rate: u32,
+
+    /// Connections per second accepted at the end of the ramp.
+    pub final_rate: u32,
+
+    /// How long after startup connections are limited.
+    pub ramp_duration: Duration,
+}
+
+impl SlowStart {
+    #[must_use]
+    pub fn new(initial_rate: u32, final_rate: u32, ramp_duration: Duration) -> Self {
+        SlowStart {
+            initial_rate,
+            final_rate,
+            ramp_duration,
+        }
+    }
+
+    /// The number of connections per second accepted `elapsed` after startup,
+    /// or `None` if connections are no longer limited.
+    #[must_use]
+    pub fn rate_at(&self, elapsed: Duration) -> Option<f64> {
+        if elapsed >= self.ramp_duration {
+            return None;
+        }
+
+        let progress = elapsed.as_secs_f64() / self.ramp_duration.as_secs_f64();
+        let initial = f64::from(self.initial_rate);
+        let final_rate = f64::from(self.final_rate);
+
+        Some(initial + (final_rate - initial) * progress)
+    }
+}
+
+/// A token bucket that applies a [SlowStart] policy, refilled at the rate the
+/// policy allows at each point in time. The bucket holds up to one second's
+/// worth of connections, and starts with one.
+pub struct SlowStartLimiter {
+    policy: SlowStart,
+    started: Instant,
+    /// Available tokens, and when they were last refilled.
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl SlowStartLimiter {
+    #[must_use]
+    pub fn new(policy: SlowStart, started: Instant) -> Self {
+        SlowStartLimiter {
+            policy,
+            started,
+            bucket: Mutex::new((1.0, started)),
+        }
+    }
+
+    /// Returns `true` if a connection arriving at `now` may be accepted.
+    pub fn try_acquire(&self, now: Instant) -> bool {
+        let since_start = now.saturating_duration_since(self.started);
+        let rate = match self.policy.rate_at(since_start) {
+            Some(rate) => rate,
+            None => return true,
+        };
+
+        let mut bucket = self.bucket.lock().unwrap();
+        let (tokens, last_refill) = &mut *bucket;
+
+        let elapsed = now.saturating_duration_since(*last_refill).as_secs_f64();
+        *tokens = (*tokens + elapsed * rate).min(rate.max(1.0));
+        *last_refill = now.max(*last_refill);
+
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ramp_bounds_accepted_connections() {
+        let started = Instant::now();
+        let limiter =
+            SlowStartLimiter::new(SlowStart::new(10, 100, Duration::from_secs(10)), started);
+
+        // A connection attempt every millisecond. Over each second of the ramp,
+        // roughly as many are accepted as the rate at that point in the ramp.
+        let mut accepted = vec![0; 11];
+        for ms in 0..11_000 {
+            if limiter.try_acquire(started + Duration::from_millis(ms)) {
+                accepted[(ms / 1000) as usize] += 1;
+            }
+        }
+
+        assert!((10..=16).contains(&accepted[0]), "accepted {:?}", accepted);
+        assert!((45..=55).contains(&accepted[4]), "accepted {:?}", accepted);
+        assert!(accepted[..10].windows(2).all(|pair| pair[0] <= pair[1]));
+
+        // Once the ramp is over, every connection is accepted.
+        assert_eq!(1000, accepted[10]);
+    }
+}
@@ -0,0 +1,124 @@
+use crate::metrics::{Direction, Metrics};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How far back the message rate reported at `/stats` looks.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Aggregate server statistics, returned by the `/stats` endpoint.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Stats {
+    pub active_rooms: u64,
+    pub rooms_created: u64,
+    pub connected_clients: u64,
+    /// Messages passing through the server in either direction.
+    pub messages_total: u64,
+    /// Messages per second over roughly the last minute.
+    pub messages_per_second: f64,
+    pub uptime_seconds: u64,
+    /// The version of `stateroom-server`.
+    pub version: &'static str,
+}
+
+/// Tracks the total message count each time stats are requested, in order to
+/// report a recent message rate.
+pub struct MessageRate {
+    /// Times and message totals of past samples within [RATE_WINDOW], oldest
+    /// first. Starts with a sample of zero messages at startup.
+    samples: Mutex<VecDeque<(Instant, u64)>>,
+}
+
+impl MessageRate {
+    #[must_use]
+    pub fn new(started: Instant) -> Self {
+        MessageRate {
+            samples: Mutex::new(VecDeque::from([(started, 0)])),
+        }
+    }
+
+    /// Record that `total` messages had been sent by `now`, and return the rate
+    /// since the oldest sample still within the window.
+    pub fn sample(&self, now: Instant, total: u64) -> f64 {
+        let mut samples = self.samples.lock().unwrap();
+
+        // Keep the newest sample older than the window, so that the rate
+        // always covers at least a full window once one has passed.
+        while samples.len() > 1 && now.saturating_duration_since(samples[1].0) >= RATE_WINDOW {
+            samples.pop_front();
+        }
+
+        let rate = match samples.front() {
+            Some((since, since_total)) if now > *since => {
+                total.saturating_sub(*since_total) as f64 / now.duration_since(*since).as_secs_f64()
+            }
+            _ => 0.0,
+        };
+
+        samples.push_back((now, total));
+        rate
+    }
+}
+
+/// Compares a presented admin token with the expected one in time that
+/// depends only on their lengths, not on where they differ.
+pub(crate) fn token_matches(presented: &str, expected: &str) -> bool {
+    let (presented, expected) = (presented.as_bytes(), expected.as_bytes());
+    if presented.len() != expected.len() {
+        return false;
+    }
+
+    presented
+        .iter()
+        .zip(expected)
+        .fold(0, |difference, (a, b)| difference | (a ^ b))
+        == 0
+}
+
+impl Stats {
+    #[must_use]
+    pub fn collect(metrics: &Metrics, rate: &MessageRate, started: Instant, now: Instant) -> Self {
+        let messages_total =
+            metrics.messages(Direction::Inbound) + metrics.messages(Direction::Outbound);
+
+        Stats {
+            active_rooms: metrics.active_rooms(),
+            rooms_created: metrics.rooms_created(),
+            connected_clients: metrics.connected_clients(),
+            messages_total,
+            messages_per_second: rate.sample(now, messages_total),
+            uptime_seconds: now.saturating_duration_since(started).as_secs(),
+            version: env!("CARGO_PKG_VERSION"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_matches() {
+        assert!(token_matches("secret", "secret"));
+        assert!(!token_matches("secreT", "secret"));
+        assert!(!token_matches("secret2", "secret"));
+        assert!(!token_matches("", "secret"));
+    }
+
+    #[test]
+    fn test_message_rate_window() {
+        let started = Instant::now();
+        let rate = MessageRate::new(started);
+        let at = |secs| started + Duration::from_secs(secs);
+
+        assert_eq!(10.0, rate.sample(at(10), 100));
+        assert_eq!(10.0, rate.sample(at(40), 400));
+
+        // Samples older than the window stop counting towards the rate.
+        assert_eq!(0.0, rate.sample(at(120), 400));
+        assert_eq!(1.0, rate.sample(at(140), 500));
+    }
+}
@@ -0,0 +1,105 @@
+use crate::messages::{
+    AuthorizationResult, CloseConnection, MessageBatch, MessageData, MessageFromClient,
+    MessageFromServer,
+};
+use actix::{Actor, Context, Handler, Message, MessageResult};
+use stateroom::{ClientId, DisconnectReason, MessageRecipient};
+
+/// A simplified, comparable record of a message received by a [Recorder].
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    Connect(ClientId),
+    Disconnect(ClientId, DisconnectReason),
+    FromClient(ClientId, String),
+    FromServer(MessageRecipient, String),
+    Close(DisconnectReason),
+    Authorization(ClientId, Result<(), String>),
+    /// The messages of a [MessageBatch], each as a [Event::FromServer].
+    Batch(Vec<Event>),
+}
+
+fn describe(data: &MessageData) -> String {
+    match data {
+        MessageData::String(st) => st.to_string(),
+        MessageData::Binary(bin) => format!("{:?}", bin.to_vec()),
+    }
+}
+
+/// An actor that stands in for a service actor or a client connection,
+/// recording every message it receives.
+#[derive(Default)]
+pub struct Recorder {
+    events: Vec<Event>,
+}
+
+impl Actor for Recorder {
+    type Context = Context<Self>;
+}
+
+impl Handler<MessageFromClient> for Recorder {
+    type Result = ();
+
+    fn handle(&mut self, msg: MessageFromClient, _: &mut Self::Context) {
+        self.events.push(match msg {
+            MessageFromClient::Connect(client, _, _, _) => Event::Connect(client),
+            MessageFromClient::Disconnect(client, reason) => Event::Disconnect(client, reason),
+            MessageFromClient::Message { from_client, data } => {
+                Event::FromClient(from_client, describe(&data))
+            }
+        });
+    }
+}
+
+impl Handler<MessageFromServer> for Recorder {
+    type Result = ();
+
+    fn handle(&mut self, msg: MessageFromServer, _: &mut Self::Context) {
+        self.events
+            .push(Event::FromServer(msg.to_client, describe(&msg.data)));
+    }
+}
+
+impl Handler<MessageBatch> for Recorder {
+    type Result = ();
+
+    fn handle(&mut self, MessageBatch(messages): MessageBatch, _: &mut Self::Context) {
+        self.events.push(Event::Batch(
+            messages
+                .into_iter()
+                .map(|msg| Event::FromServer(msg.to_client, describe(&msg.data)))
+                .collect(),
+        ));
+    }
+}
+
+impl Handler<AuthorizationResult> for Recorder {
+    type Result = ();
+
+    fn handle(&mut self, msg: AuthorizationResult, _: &mut Self::Context) {
+        self.events
+            .push(Event::Authorization(msg.client, msg.result));
+    }
+}
+
+impl Handler<CloseConnection> for Recorder {
+    type Result = ();
+
+    fn handle(&mut self, msg: CloseConnection, _: &mut Self::Context) {
+        self.events.push(Event::Close(msg.reason));
+    }
+}
+
+/// Returns and clears the events recorded so far.
+pub struct TakeEvents;
+
+impl Message for TakeEvents {
+    type Result = Vec<Event>;
+}
+
+impl Handler<TakeEvents> for Recorder {
+    type Result = MessageResult<TakeEvents>;
+
+    fn handle(&mut self, _: TakeEvents, _: &mut Self::Context) -> Self::Result {
+        MessageResult(std::mem::take(&mut self.events))
+    }
+}
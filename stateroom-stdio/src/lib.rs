@@ -1,13 +1,85 @@
-use std::process::Command;
+use std::{
+    collections::BTreeMap,
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::Duration,
+};
 
 use interactive_process::InteractiveProcess;
 use stateroom::{
-    MessageFromProcess, MessagePayload, MessageToProcess, StateroomContext, StateroomService,
-    StateroomServiceFactory,
+    AcceptedMessages, ClientId, ConnectionMetadata, DisconnectReason, MessageFromProcess,
+    MessagePayload, MessageToProcess, StateroomContext, StateroomService, StateroomServiceFactory,
 };
 
+/// Determines how a [StdioProcessService] recovers when its child process exits.
+#[derive(Clone, Debug)]
+pub struct RestartPolicy {
+    /// The number of times to attempt to restart the process after a crash
+    /// before giving up.
+    pub max_retries: u32,
+
+    /// The delay before the first restart attempt. Each subsequent attempt
+    /// waits twice as long as the previous one.
+    pub backoff: Duration,
+}
+
+/// The optional features a process supports, as declared in its
+/// [MessageFromProcess::Capabilities] message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProcessCapabilities {
+    /// Whether the process is sent `Timer` messages.
+    pub timer: bool,
+    /// Whether the process is sent binary messages. If not, binary messages
+    /// from clients are dropped.
+    pub binary: bool,
+}
+
+impl ProcessCapabilities {
+    /// The capabilities assumed of a process that has not declared any.
+    pub const ALL: ProcessCapabilities = ProcessCapabilities {
+        timer: true,
+        binary: true,
+    };
+}
+
+/// Capabilities declared by a running process, shared with the thread that
+/// reads its output.
+#[derive(Default)]
+struct DeclaredCapabilities {
+    capabilities: Mutex<Option<ProcessCapabilities>>,
+    declared: Condvar,
+}
+
+impl DeclaredCapabilities {
+    fn declare(&self, capabilities: ProcessCapabilities) {
+        *self.capabilities.lock().unwrap() = Some(capabilities);
+        self.declared.notify_all();
+    }
+
+    fn get(&self) -> Option<ProcessCapabilities> {
+        *self.capabilities.lock().unwrap()
+    }
+
+    /// Wait up to `timeout` for the process to declare its capabilities.
+    fn wait(&self, timeout: Duration) -> Option<ProcessCapabilities> {
+        let capabilities = self.capabilities.lock().unwrap();
+        let (capabilities, _) = self
+            .declared
+            .wait_timeout_while(capabilities, timeout, |capabilities| capabilities.is_none())
+            .unwrap();
+        *capabilities
+    }
+}
+
 pub struct StdioProcessServiceFactory {
     command: String,
+    restart_policy: Option<RestartPolicy>,
+    handshake_timeout: Option<Duration>,
+    /// The capabilities declared during the most recent handshake.
+    capabilities: Arc<Mutex<Option<ProcessCapabilities>>>,
 }
 
 impl StdioProcessServiceFactory {
@@ -15,76 +87,763 @@ impl StdioProcessServiceFactory {
     pub fn new(command: &str) -> Self {
         StdioProcessServiceFactory {
             command: command.to_string(),
+            restart_policy: None,
+            handshake_timeout: None,
+            capabilities: Arc::default(),
+        }
+    }
+
+    /// Restart the child process if it exits, replaying a `Connect` message for
+    /// each client that is currently connected. Restarts happen on a background
+    /// thread; messages sent in the meantime are held and delivered in order
+    /// once the new process is running.
+    #[must_use]
+    pub fn with_restart_policy(mut self, max_retries: u32, backoff: Duration) -> Self {
+        self.restart_policy = Some(RestartPolicy {
+            max_retries,
+            backoff,
+        });
+        self
+    }
+
+    /// Require each process to declare its capabilities with a
+    /// [MessageFromProcess::Capabilities] message when it starts. Building a
+    /// service fails if the process does not do so within `timeout`, and the
+    /// process is killed. Restarted processes go through the same handshake,
+    /// and a restart attempt fails if it times out.
+    ///
+    /// Without a handshake, a process is assumed to support everything until
+    /// it declares otherwise.
+    #[must_use]
+    pub fn with_capabilities_handshake(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// The capabilities declared by the most recently started process during
+    /// its handshake, including restarted processes, or `None` if no handshake
+    /// has completed.
+    #[must_use]
+    pub fn capabilities(&self) -> Option<ProcessCapabilities> {
+        *self.capabilities.lock().unwrap()
+    }
+}
+
+fn handle_line(
+    context: &impl StateroomContext,
+    capabilities: &DeclaredCapabilities,
+    line: std::io::Result<String>,
+) {
+    let line = match line {
+        Ok(line) => line,
+        Err(error) => {
+            tracing::error!(?error, "Error reading line from process.");
+            return;
+        }
+    };
+
+    let message: MessageFromProcess = match serde_json::from_str(&line) {
+        Ok(message) => message,
+        Err(error) => {
+            tracing::error!(?error, %line, "Couldn't parse message from process.");
+            return;
+        }
+    };
+
+    match message {
+        MessageFromProcess::Message {
+            recipient,
+            message: MessagePayload::Bytes(message),
+        } => {
+            context.send_binary(recipient, &message);
+        }
+        MessageFromProcess::Message {
+            recipient,
+            message: MessagePayload::Text(message),
+        } => {
+            context.send_message(recipient, &message);
+        }
+        MessageFromProcess::SetTimer { duration_ms } => {
+            context.set_timer(duration_ms);
+        }
+        MessageFromProcess::Capabilities { timer, binary } => {
+            let declared = ProcessCapabilities { timer, binary };
+            tracing::info!(?declared, "Process declared capabilities.");
+            capabilities.declare(declared);
         }
     }
 }
 
-impl<T: StateroomContext + Send + Sync + 'static> StateroomServiceFactory<T> for StdioProcessServiceFactory {
+/// Stop a process that will not be used, and wait for it to exit.
+fn kill(process: InteractiveProcess) {
+    let mut child = process.close();
+    if let Err(error) = child.kill() {
+        tracing::error!(?error, "Could not kill process.");
+    }
+    let _ = child.wait();
+}
+
+type Spawner = Box<
+    dyn Fn(Arc<AtomicBool>, Arc<DeclaredCapabilities>) -> std::io::Result<InteractiveProcess>
+        + Send
+        + Sync
+        + 'static,
+>;
+
+impl<T: StateroomContext + Send + Sync + 'static> StateroomServiceFactory<T>
+    for StdioProcessServiceFactory
+{
     type Service = StdioProcessService;
     type Error = std::io::Error;
 
     fn build(&self, _room_id: &str, context: T) -> Result<Self::Service, Self::Error> {
-        let process = InteractiveProcess::new(Command::new(&self.command), move |line| {
-            let line = line.expect("Error reading line from stdin.");
-            let message: MessageFromProcess =
-                serde_json::from_str(&line).expect("Couldn't parse message from process.");
-
-            match message {
-                MessageFromProcess::Message {
-                    recipient,
-                    message: MessagePayload::Bytes(message),
-                } => {
-                    context.send_binary(recipient, &message);
-                }
-                MessageFromProcess::Message {
-                    recipient,
-                    message: MessagePayload::Text(message),
-                } => {
-                    context.send_message(recipient, &message);
+        let context = Arc::new(context);
+        let command = self.command.clone();
+        let handshake_timeout = self.handshake_timeout;
+        let handshake_capabilities = self.capabilities.clone();
+
+        // Restarted processes are spawned the same way, so they go through the
+        // handshake too.
+        let spawn: Spawner = Box::new(
+            move |exited: Arc<AtomicBool>, capabilities: Arc<DeclaredCapabilities>| {
+                let context = context.clone();
+                let declared = capabilities.clone();
+                let process = InteractiveProcess::new_with_exit_callback(
+                    Command::new(&command),
+                    move |line| handle_line(context.as_ref(), &declared, line),
+                    move || exited.store(true, Ordering::SeqCst),
+                )?;
+
+                if let Some(timeout) = handshake_timeout {
+                    if let Some(declared) = capabilities.wait(timeout) {
+                        *handshake_capabilities.lock().unwrap() = Some(declared);
+                    } else {
+                        kill(process);
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            "Process did not declare its capabilities.",
+                        ));
+                    }
                 }
+
+                Ok(process)
+            },
+        );
+
+        let exited = Arc::new(AtomicBool::new(false));
+        let capabilities = Arc::new(DeclaredCapabilities::default());
+        let process = spawn(exited.clone(), capabilities.clone())?;
+
+        Ok(StdioProcessService {
+            slot: Arc::new(Mutex::new(ProcessSlot {
+                process: Some(process),
+                exited,
+                capabilities,
+                clients: BTreeMap::new(),
+                restarting: false,
+                queued: Vec::new(),
+            })),
+            spawn: Arc::new(spawn),
+            restart_policy: self.restart_policy.clone(),
+        })
+    }
+}
+
+/// The maximum number of messages held for a process while it restarts.
+/// Further messages are dropped.
+const MAX_QUEUED_MESSAGES: usize = 256;
+
+/// The child process, shared between the service and the thread that
+/// restarts it.
+struct ProcessSlot {
+    process: Option<InteractiveProcess>,
+    /// Set by the process's exit callback when its `stdout` stream closes.
+    exited: Arc<AtomicBool>,
+    /// Capabilities declared by the running process.
+    capabilities: Arc<DeclaredCapabilities>,
+    /// Clients currently connected and the metadata they connected with, used
+    /// to replay `Connect` messages to a restarted process.
+    clients: BTreeMap<ClientId, ConnectionMetadata>,
+    /// Whether a background thread is currently restarting the process.
+    restarting: bool,
+    /// Messages sent while the process restarts, as the lines to send it once
+    /// it is running.
+    queued: Vec<String>,
+}
+
+impl ProcessSlot {
+    fn is_running(&self) -> bool {
+        self.process.is_some() && !self.exited.load(Ordering::SeqCst)
+    }
+
+    fn try_send(&mut self, message: &MessageToProcess) -> bool {
+        match encode(message) {
+            Some(line) => self.try_send_line(&line),
+            None => false,
+        }
+    }
+
+    fn try_send_line(&mut self, line: &str) -> bool {
+        if let Some(process) = &mut self.process {
+            match process.send(line) {
+                Ok(()) => return true,
+                Err(error) => tracing::error!(?error, "Could not send message to process."),
             }
-        })?;
+        }
+
+        false
+    }
+
+    /// Hold a message until the restarted process is running. Connects and
+    /// disconnects are not held, since the restarted process is sent a
+    /// `Connect` for each client that is connected by then.
+    fn queue(&mut self, message: &MessageToProcess) {
+        if matches!(
+            message,
+            MessageToProcess::Connect { .. } | MessageToProcess::Disconnect { .. }
+        ) {
+            return;
+        }
+
+        if self.queued.len() >= MAX_QUEUED_MESSAGES {
+            tracing::warn!("Dropping message to process that is restarting.");
+        } else if let Some(line) = encode(message) {
+            self.queued.push(line);
+        }
+    }
+}
 
-        Ok(StdioProcessService { process })
+fn encode(message: &MessageToProcess) -> Option<String> {
+    match serde_json::to_string(message) {
+        Ok(line) => Some(line),
+        Err(error) => {
+            tracing::error!(?error, "Could not jsonify message.");
+            None
+        }
     }
 }
 
 pub struct StdioProcessService {
-    process: InteractiveProcess,
+    slot: Arc<Mutex<ProcessSlot>>,
+    spawn: Arc<Spawner>,
+    restart_policy: Option<RestartPolicy>,
 }
 
 impl StdioProcessService {
+    fn capabilities(&self) -> ProcessCapabilities {
+        self.slot
+            .lock()
+            .unwrap()
+            .capabilities
+            .get()
+            .unwrap_or(ProcessCapabilities::ALL)
+    }
+
+    /// Start restarting the process according to the restart policy, on a
+    /// background thread so that the backoff does not block the service's
+    /// caller. Returns `false` if the process will not be restarted.
+    fn begin_restart(&self, slot: &mut ProcessSlot) -> bool {
+        if let Some(process) = slot.process.take() {
+            kill(process);
+        }
+
+        let policy = if let Some(policy) = &self.restart_policy {
+            policy.clone()
+        } else {
+            return false;
+        };
+
+        slot.restarting = true;
+        let shared_slot = self.slot.clone();
+        let spawn = self.spawn.clone();
+
+        std::thread::spawn(move || {
+            let mut delay = policy.backoff;
+            for attempt in 1..=policy.max_retries {
+                std::thread::sleep(delay);
+                delay *= 2;
+
+                let exited = Arc::new(AtomicBool::new(false));
+                let capabilities = Arc::new(DeclaredCapabilities::default());
+                match spawn(exited.clone(), capabilities.clone()) {
+                    Ok(process) => {
+                        tracing::info!(%attempt, "Restarted process.");
+                        let mut slot = shared_slot.lock().unwrap();
+                        slot.process = Some(process);
+                        slot.exited = exited;
+                        slot.capabilities = capabilities;
+                        slot.restarting = false;
+
+                        for (client, metadata) in slot.clients.clone() {
+                            slot.try_send(&MessageToProcess::Connect { client, metadata });
+                        }
+                        for line in std::mem::take(&mut slot.queued) {
+                            slot.try_send_line(&line);
+                        }
+                        return;
+                    }
+                    Err(error) => {
+                        tracing::error!(?error, %attempt, "Could not restart process.");
+                    }
+                }
+            }
+
+            tracing::error!("Giving up on restarting process.");
+            let mut slot = shared_slot.lock().unwrap();
+            slot.restarting = false;
+            slot.queued.clear();
+        });
+
+        true
+    }
+
     fn send_to_process(&mut self, message: &MessageToProcess) {
-        self.process
-            .send(&serde_json::to_string(&message).expect("Could not jsonify message."))
-            .expect("Could not send message to process.");
+        let mut slot = self.slot.lock().unwrap();
+
+        if slot.restarting {
+            slot.queue(message);
+            return;
+        }
+
+        if !slot.is_running() {
+            tracing::warn!("Process is not running.");
+        } else if slot.try_send(message) {
+            return;
+        }
+
+        if self.begin_restart(&mut slot) {
+            slot.queue(message);
+        } else {
+            tracing::error!("Dropping message because process is not running.");
+        }
     }
 }
 
 impl StateroomService for StdioProcessService {
-    fn connect(&mut self, client: stateroom::ClientId) {
-        self.send_to_process(&MessageToProcess::Connect { client });
+    fn connect(&mut self, client: ClientId) {
+        let _ = self.connect_with_metadata(client, &ConnectionMetadata::default());
+    }
+
+    fn connect_with_metadata(
+        &mut self,
+        client: ClientId,
+        metadata: &ConnectionMetadata,
+    ) -> Result<(), String> {
+        // Recorded first, so that a process restarted by this message is sent
+        // the `Connect` when it starts.
+        self.slot
+            .lock()
+            .unwrap()
+            .clients
+            .insert(client, metadata.clone());
+        self.send_to_process(&MessageToProcess::Connect {
+            client,
+            metadata: metadata.clone(),
+        });
+        Ok(())
+    }
+
+    fn disconnect(&mut self, client: ClientId) {
+        self.disconnect_with_reason(client, DisconnectReason::default());
     }
 
-    fn disconnect(&mut self, client: stateroom::ClientId) {
-        self.send_to_process(&MessageToProcess::Disconnect { client });
+    fn disconnect_with_reason(&mut self, client: ClientId, reason: DisconnectReason) {
+        self.slot.lock().unwrap().clients.remove(&client);
+        self.send_to_process(&MessageToProcess::Disconnect { client, reason });
     }
 
-    fn message(&mut self, sender: stateroom::ClientId, message: &str) {
+    fn message(&mut self, sender: ClientId, message: &str) {
         self.send_to_process(&MessageToProcess::Message {
             client: sender,
             message: MessagePayload::Text(message.to_string()),
         });
     }
 
-    fn binary(&mut self, sender: stateroom::ClientId, message: &[u8]) {
+    fn binary(&mut self, sender: ClientId, message: &[u8]) {
+        if !self.capabilities().binary {
+            tracing::warn!("Dropping binary message to process that does not support them.");
+            return;
+        }
+
         self.send_to_process(&MessageToProcess::Message {
             client: sender,
             message: MessagePayload::Bytes(message.to_vec()),
         });
     }
 
+    fn accepted_messages(&self) -> AcceptedMessages {
+        AcceptedMessages {
+            text: true,
+            binary: self.capabilities().binary,
+        }
+    }
+
     fn timer(&mut self) {
+        if !self.capabilities().timer {
+            return;
+        }
+
         self.send_to_process(&MessageToProcess::Timer);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stateroom::MessageRecipient;
+    use std::{os::unix::fs::PermissionsExt, path::PathBuf, sync::Mutex, time::Instant};
+
+    type Log<T> = Arc<Mutex<Vec<T>>>;
+
+    #[derive(Clone, Default)]
+    struct MockContext {
+        messages: Log<(MessageRecipient, String)>,
+        binaries: Log<(MessageRecipient, Vec<u8>)>,
+        timers: Log<u32>,
+    }
+
+    impl StateroomContext for MockContext {
+        fn send_message(&self, recipient: impl Into<MessageRecipient>, message: &str) {
+            self.messages
+                .lock()
+                .unwrap()
+                .push((recipient.into(), message.to_string()));
+        }
+
+        fn send_binary(&self, recipient: impl Into<MessageRecipient>, message: &[u8]) {
+            self.binaries
+                .lock()
+                .unwrap()
+                .push((recipient.into(), message.to_vec()));
+        }
+
+        fn set_timer(&self, ms_delay: u32) {
+            self.timers.lock().unwrap().push(ms_delay);
+        }
+    }
+
+    fn write_script(name: &str, body: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "stateroom-stdio-{}-{}.sh",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, format!("#!/bin/sh\n{}", body)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    fn exited(service: &StdioProcessService) -> bool {
+        service.slot.lock().unwrap().exited.load(Ordering::SeqCst)
+    }
+
+    fn wait_for(condition: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !condition() {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "Timed out waiting for condition."
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_restart_after_crash() {
+        let script = write_script(
+            "restart",
+            r#"
+while read -r line; do
+    case "$line" in
+        *crash*) exit 1 ;;
+        *Connect*) echo '{"type":"Message","recipient":"Broadcast","message":{"Text":"hello"}}' ;;
+    esac
+done
+"#,
+        );
+
+        let context = MockContext::default();
+        let factory = StdioProcessServiceFactory::new(script.to_str().unwrap())
+            .with_restart_policy(3, Duration::from_millis(10));
+        let mut service = factory.build("", context.clone()).unwrap();
+
+        let hello_count = || context.messages.lock().unwrap().len();
+
+        service.connect(1.into());
+        wait_for(|| hello_count() == 1);
+
+        service.message(1.into(), "crash");
+        wait_for(|| exited(&service));
+
+        // The next message restarts the process, which replays the `Connect`.
+        service.message(1.into(), "ping");
+        wait_for(|| hello_count() == 2);
+
+        assert_eq!(
+            (MessageRecipient::Broadcast, "hello".to_string()),
+            context.messages.lock().unwrap()[1]
+        );
+
+        std::fs::remove_file(script).unwrap();
+    }
+
+    #[test]
+    fn test_restart_reaps_old_process() {
+        let pid_file =
+            std::env::temp_dir().join(format!("stateroom-stdio-reap-{}.pid", std::process::id()));
+        // Appends its PID to the file each time it starts.
+        let script = write_script(
+            "reap",
+            &format!("echo $$ >> {}\nread -r line\nexit 1\n", pid_file.display()),
+        );
+
+        let factory = StdioProcessServiceFactory::new(script.to_str().unwrap())
+            .with_restart_policy(3, Duration::from_millis(10));
+        let mut service = factory.build("", MockContext::default()).unwrap();
+
+        service.message(1.into(), "crash");
+        wait_for(|| exited(&service));
+        service.message(1.into(), "ping");
+        wait_for(|| !service.slot.lock().unwrap().restarting);
+
+        // The crashed process has been waited on, rather than left a zombie.
+        let pids = std::fs::read_to_string(&pid_file).unwrap();
+        let first = pids.lines().next().unwrap();
+        assert!(!PathBuf::from(format!("/proc/{}", first)).exists());
+
+        std::fs::remove_file(pid_file).unwrap();
+        std::fs::remove_file(script).unwrap();
+    }
+
+    #[test]
+    fn test_restart_does_not_block() {
+        let script = write_script(
+            "restart-backoff",
+            r#"
+while read -r line; do
+    case "$line" in
+        *crash*) exit 1 ;;
+        *ping*) echo '{"type":"Message","recipient":"Broadcast","message":{"Text":"pong"}}' ;;
+    esac
+done
+"#,
+        );
+
+        let context = MockContext::default();
+        let factory = StdioProcessServiceFactory::new(script.to_str().unwrap())
+            .with_restart_policy(3, Duration::from_millis(500));
+        let mut service = factory.build("", context.clone()).unwrap();
+
+        service.message(1.into(), "crash");
+        wait_for(|| exited(&service));
+
+        // Messages sent during the backoff return immediately, and are
+        // delivered once the process has restarted.
+        let start = Instant::now();
+        service.message(1.into(), "ping");
+        service.message(1.into(), "ping");
+        assert!(start.elapsed() < Duration::from_millis(250));
+
+        wait_for(|| context.messages.lock().unwrap().len() == 2);
+
+        std::fs::remove_file(script).unwrap();
+    }
+
+    #[test]
+    fn test_no_restart_does_not_panic() {
+        let script = write_script("no-restart", "read -r line\nexit 1\n");
+
+        let factory = StdioProcessServiceFactory::new(script.to_str().unwrap());
+        let mut service = factory.build("", MockContext::default()).unwrap();
+
+        service.message(1.into(), "crash");
+        wait_for(|| exited(&service));
+
+        service.message(1.into(), "ignored");
+        service.timer();
+        assert!(!service.slot.lock().unwrap().is_running());
+
+        std::fs::remove_file(script).unwrap();
+    }
+
+    #[test]
+    fn test_process_sets_timer() {
+        let script = write_script(
+            "timer",
+            r#"
+while read -r line; do
+    case "$line" in
+        *Connect*) echo '{"type":"SetTimer","duration_ms":250}' ;;
+        *Timer*) echo '{"type":"Message","recipient":"Broadcast","message":{"Text":"tick"}}' ;;
+    esac
+done
+"#,
+        );
+
+        let context = MockContext::default();
+        let factory = StdioProcessServiceFactory::new(script.to_str().unwrap());
+        let mut service = factory.build("", context.clone()).unwrap();
+
+        service.connect(1.into());
+        wait_for(|| !context.timers.lock().unwrap().is_empty());
+        assert_eq!(vec![250], *context.timers.lock().unwrap());
+
+        // Stand in for the host firing the timer.
+        service.timer();
+        wait_for(|| !context.messages.lock().unwrap().is_empty());
+        assert_eq!(
+            vec![(MessageRecipient::Broadcast, "tick".to_string())],
+            *context.messages.lock().unwrap()
+        );
+
+        std::fs::remove_file(script).unwrap();
+    }
+
+    #[test]
+    fn test_capabilities_handshake() {
+        let script = write_script(
+            "capabilities",
+            r#"
+echo '{"type":"Capabilities","binary":true}'
+while read -r line; do
+    case "$line" in
+        *Timer*) echo '{"type":"Message","recipient":"Broadcast","message":{"Text":"tick"}}' ;;
+        *Connect*) echo '{"type":"Message","recipient":"Broadcast","message":{"Text":"hello"}}' ;;
+    esac
+done
+"#,
+        );
+
+        let context = MockContext::default();
+        let factory = StdioProcessServiceFactory::new(script.to_str().unwrap())
+            .with_capabilities_handshake(Duration::from_secs(5));
+        let mut service = factory.build("", context.clone()).unwrap();
+
+        assert_eq!(
+            Some(ProcessCapabilities {
+                timer: false,
+                binary: true,
+            }),
+            factory.capabilities()
+        );
+
+        // The process would answer a timer before the connection, if it were sent one.
+        service.timer();
+        service.connect(1.into());
+        wait_for(|| !context.messages.lock().unwrap().is_empty());
+        assert_eq!(
+            vec![(MessageRecipient::Broadcast, "hello".to_string())],
+            *context.messages.lock().unwrap()
+        );
+
+        std::fs::remove_file(script).unwrap();
+    }
+
+    #[test]
+    fn test_handshake_timeout() {
+        let pid_file = std::env::temp_dir().join(format!(
+            "stateroom-stdio-no-handshake-{}.pid",
+            std::process::id()
+        ));
+        // Ignores its input, so that it only exits if it is killed.
+        let script = write_script(
+            "no-handshake",
+            &format!("echo $$ > {}\nexec sleep 30\n", pid_file.display()),
+        );
+
+        let factory = StdioProcessServiceFactory::new(script.to_str().unwrap())
+            .with_capabilities_handshake(Duration::from_millis(200));
+        let error = factory.build("", MockContext::default()).err().unwrap();
+        assert_eq!(std::io::ErrorKind::TimedOut, error.kind());
+        assert_eq!(None, factory.capabilities());
+
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        assert!(!PathBuf::from(format!("/proc/{}", pid.trim())).exists());
+
+        std::fs::remove_file(pid_file).unwrap();
+        std::fs::remove_file(script).unwrap();
+    }
+
+    #[test]
+    fn test_handshake_after_restart() {
+        let marker = std::env::temp_dir().join(format!(
+            "stateroom-stdio-restart-handshake-{}.marker",
+            std::process::id()
+        ));
+        // Declares binary support the first time it runs, but not after that.
+        let script = write_script(
+            "restart-handshake",
+            &format!(
+                r#"
+if [ -e {marker} ]; then
+    echo '{{"type":"Capabilities","binary":false}}'
+else
+    touch {marker}
+    echo '{{"type":"Capabilities","binary":true}}'
+fi
+while read -r line; do
+    case "$line" in
+        *crash*) exit 1 ;;
+    esac
+done
+"#,
+                marker = marker.display()
+            ),
+        );
+
+        let factory = StdioProcessServiceFactory::new(script.to_str().unwrap())
+            .with_restart_policy(3, Duration::from_millis(10))
+            .with_capabilities_handshake(Duration::from_secs(5));
+        let mut service = factory.build("", MockContext::default()).unwrap();
+        assert_eq!(AcceptedMessages::ALL, service.accepted_messages());
+
+        service.message(1.into(), "crash");
+        wait_for(|| exited(&service));
+        service.message(1.into(), "ping");
+        wait_for(|| !service.slot.lock().unwrap().restarting);
+
+        assert_eq!(
+            Some(ProcessCapabilities {
+                timer: false,
+                binary: false,
+            }),
+            factory.capabilities()
+        );
+        assert_eq!(AcceptedMessages::TEXT_ONLY, service.accepted_messages());
+
+        std::fs::remove_file(marker).unwrap();
+        std::fs::remove_file(script).unwrap();
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        // Echoes the (base64-encoded) payload of each binary message back.
+        let script = write_script(
+            "binary",
+            r#"
+while read -r line; do
+    case "$line" in
+        *Bytes*)
+            payload=$(echo "$line" | sed 's/.*"Bytes":"\([^"]*\)".*/\1/')
+            echo "{\"type\":\"Message\",\"recipient\":\"Broadcast\",\"message\":{\"Bytes\":\"$payload\"}}"
+            ;;
+    esac
+done
+"#,
+        );
+
+        let context = MockContext::default();
+        let factory = StdioProcessServiceFactory::new(script.to_str().unwrap());
+        let mut service = factory.build("", context.clone()).unwrap();
+
+        let bytes = vec![0x00, 0x0A, b'"', b'\\', 0xFF, 0x0D, 0x0A];
+        service.binary(1.into(), &bytes);
+        wait_for(|| !context.binaries.lock().unwrap().is_empty());
+        assert_eq!(
+            vec![(MessageRecipient::Broadcast, bytes)],
+            *context.binaries.lock().unwrap()
+        );
+
+        std::fs::remove_file(script).unwrap();
+    }
+}
@@ -0,0 +1,129 @@
+//! Compares [EngineMode::Shared] with [EngineMode::PerThread] when many threads
+//! create rooms and send messages to them at the same time.
+//!
+//! Run with `cargo bench -p stateroom-wasm-host`. The number of threads
+//! defaults to the number of available cores, and can be set with the
+//! `THREADS` environment variable.
+
+use stateroom::{MessageRecipient, StateroomContext, StateroomService, StateroomServiceFactory};
+use stateroom_wasm_host::{EngineMode, WasmHostConfig, WasmHostFactory};
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Barrier,
+    },
+    time::{Duration, Instant},
+};
+
+const ROOMS_PER_THREAD: usize = 20;
+const MESSAGES_PER_ROOM: usize = 2_000;
+
+/// A guest that echoes every message back to its sender.
+const GUEST: &str = r#"(module
+    (import "env" "send_message" (func $send_message (param i32 i32 i32)))
+    (memory (export "memory") 1)
+    (global (export "JAMSOCKET_API_VERSION") i32 (i32.const 16))
+    (global (export "JAMSOCKET_API_PROTOCOL") i32 (i32.const 20))
+    (data (i32.const 16) "\04\00\00\00\00\00\00\00")
+    (func (export "initialize") (param i32 i32))
+    (func (export "jam_malloc") (param i32) (result i32) (i32.const 1024))
+    (func (export "jam_free") (param i32 i32))
+    (func (export "connect") (param i32 i32 i32) (result i32) (i32.const 0))
+    (func (export "disconnect") (param i32 i32))
+    (func (export "timer"))
+    (func (export "message") (param i32 i32 i32)
+        (call $send_message (local.get 0) (local.get 1) (local.get 2)))
+    (func (export "binary") (param i32 i32 i32))
+)"#;
+
+#[derive(Clone, Default)]
+struct CountingContext {
+    messages: Arc<AtomicUsize>,
+}
+
+impl StateroomContext for CountingContext {
+    fn send_message(&self, _: impl Into<MessageRecipient>, _: &str) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn send_binary(&self, _: impl Into<MessageRecipient>, _: &[u8]) {}
+
+    fn set_timer(&self, _: u32) {}
+}
+
+fn run(factory: &WasmHostFactory, threads: usize) -> Duration {
+    let context = CountingContext::default();
+    let barrier = Arc::new(Barrier::new(threads + 1));
+
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let factory = factory.clone();
+            let context = context.clone();
+            let barrier = barrier.clone();
+
+            std::thread::spawn(move || {
+                // Build one room before timing starts, so that in per-thread
+                // mode the thread's engine exists already, as it would on a
+                // long-running worker thread.
+                factory.build("", CountingContext::default()).unwrap();
+
+                barrier.wait();
+                for _ in 0..ROOMS_PER_THREAD {
+                    let mut host = factory.build("", context.clone()).unwrap();
+                    host.connect(1.into());
+                    for _ in 0..MESSAGES_PER_ROOM {
+                        host.message(1.into(), "ping");
+                    }
+                }
+            })
+        })
+        .collect();
+
+    barrier.wait();
+    let start = Instant::now();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    assert_eq!(
+        threads * ROOMS_PER_THREAD * MESSAGES_PER_ROOM,
+        context.messages.load(Ordering::Relaxed)
+    );
+
+    elapsed
+}
+
+fn main() {
+    let threads = std::env::var("THREADS")
+        .ok()
+        .and_then(|threads| threads.parse().ok())
+        .or_else(|| std::thread::available_parallelism().ok().map(usize::from))
+        .unwrap_or(4);
+
+    let path: PathBuf =
+        std::env::temp_dir().join(format!("stateroom-bench-{}.wat", std::process::id()));
+    std::fs::write(&path, GUEST).unwrap();
+
+    println!(
+        "{} threads, {} rooms per thread, {} messages per room",
+        threads, ROOMS_PER_THREAD, MESSAGES_PER_ROOM
+    );
+
+    for mode in [EngineMode::Shared, EngineMode::PerThread] {
+        let config = WasmHostConfig::default().with_engine_mode(mode);
+        let factory = WasmHostFactory::new_with_config(&path, config).unwrap();
+
+        let elapsed = run(&factory, threads);
+        let total = threads * ROOMS_PER_THREAD * MESSAGES_PER_ROOM;
+        println!(
+            "{:?}: {:?} ({:.0} messages/s)",
+            mode,
+            elapsed,
+            total as f64 / elapsed.as_secs_f64()
+        );
+    }
+
+    std::fs::remove_file(path).unwrap();
+}
@@ -2,14 +2,21 @@
 //! WebAssembly module. It is the counterpart to `stateroom-wasm`, which is used to
 //! implement a compatible guest module.
 
+pub use clock::{Clock, FixedClock, SystemClock};
 use std::{
     error::Error,
     fmt::{Debug, Display},
 };
 pub use wasm_host::WasmHost;
+pub use wasm_host_config::{EngineMode, WasmHostConfig};
 pub use wasm_host_factory::WasmHostFactory;
 
+mod clock;
+mod membership_log;
+#[cfg(test)]
+mod test_util;
 mod wasm_host;
+mod wasm_host_config;
 mod wasm_host_factory;
 
 /// An error encountered while running WebAssembly.
@@ -19,6 +26,7 @@ pub enum WasmRuntimeError {
     CouldNotImportGlobal,
     InvalidApiVersion,
     InvalidProtocolVersion,
+    FuelNotEnabled,
 }
 
 impl Display for WasmRuntimeError {
@@ -38,6 +46,9 @@ impl Error for WasmRuntimeError {
             Self::InvalidProtocolVersion => {
                 "WebAssembly module has an incompatible Stateroom protocol version."
             }
+            Self::FuelNotEnabled => {
+                "A fuel limit was set, but the engine does not have fuel consumption enabled."
+            }
         }
     }
 }
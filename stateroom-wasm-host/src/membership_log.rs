@@ -0,0 +1,104 @@
+use stateroom::ClientId;
+use std::collections::VecDeque;
+
+/// The number of events a [MembershipLog] holds when no size is configured.
+pub const DEFAULT_MEMBERSHIP_LOG_SIZE: usize = 1024;
+
+/// A client connecting to or disconnecting from the room.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MembershipEvent {
+    pub client: ClientId,
+    pub connected: bool,
+}
+
+impl MembershipEvent {
+    /// Encodes the event as the client ID followed by 1 if the client
+    /// connected or 0 if it disconnected, as little-endian `u32`s.
+    pub fn encode(&self) -> [u8; 8] {
+        let mut result = [0; 8];
+        result[..4].copy_from_slice(&u32::from(self.client).to_le_bytes());
+        result[4..].copy_from_slice(&u32::from(self.connected).to_le_bytes());
+        result
+    }
+}
+
+/// The most recent connects and disconnects seen by a host, numbered with a
+/// sequence that starts at 1, so that a module can ask for the changes since a
+/// sequence number it saw earlier. Only the newest `capacity` events are kept.
+pub struct MembershipLog {
+    capacity: usize,
+    /// The sequence number of the newest event, or 0 if there has been none.
+    seq: u64,
+    events: VecDeque<MembershipEvent>,
+}
+
+impl MembershipLog {
+    pub fn new(capacity: usize) -> Self {
+        MembershipLog {
+            capacity,
+            seq: 0,
+            events: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn record(&mut self, client: ClientId, connected: bool) {
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        if self.capacity > 0 {
+            self.events.push_back(MembershipEvent { client, connected });
+        }
+        self.seq += 1;
+    }
+
+    /// Returns the events after `seq`, oldest first, or `None` if some of them
+    /// have already been dropped from the log.
+    pub fn since(&self, seq: u64) -> Option<impl Iterator<Item = &MembershipEvent>> {
+        let oldest = self.seq - self.events.len() as u64;
+        if seq < oldest {
+            return None;
+        }
+
+        #[allow(clippy::cast_possible_truncation)]
+        let skip = seq.saturating_sub(oldest).min(self.events.len() as u64) as usize;
+        Some(self.events.iter().skip(skip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn changes(log: &MembershipLog, seq: u64) -> Option<Vec<(u32, bool)>> {
+        log.since(seq).map(|events| {
+            events
+                .map(|event| (event.client.into(), event.connected))
+                .collect()
+        })
+    }
+
+    #[test]
+    fn test_since_bounded() {
+        let mut log = MembershipLog::new(3);
+        assert_eq!(Some(vec![]), changes(&log, 0));
+
+        log.record(1.into(), true);
+        log.record(2.into(), true);
+        log.record(1.into(), false);
+        assert_eq!(Some(vec![(2, true), (1, false)]), changes(&log, 1));
+
+        log.record(3.into(), true);
+        assert_eq!(4, log.seq());
+        assert_eq!(None, changes(&log, 0));
+        assert_eq!(
+            Some(vec![(2, true), (1, false), (3, true)]),
+            changes(&log, 1)
+        );
+        assert_eq!(Some(vec![]), changes(&log, 4));
+        assert_eq!(Some(vec![]), changes(&log, 10));
+    }
+}
@@ -0,0 +1,72 @@
+use stateroom::{MessageRecipient, StateroomContext};
+use std::sync::{Arc, Mutex};
+use wasmtime::{Engine, Module};
+
+type Log<T> = Arc<Mutex<Vec<T>>>;
+
+/// A [StateroomContext] that records everything a guest asks of it.
+#[derive(Clone, Default)]
+pub struct MockContext {
+    pub messages: Log<(MessageRecipient, String)>,
+    pub binaries: Log<(MessageRecipient, Vec<u8>)>,
+    pub timers: Log<u32>,
+}
+
+impl MockContext {
+    pub fn take_messages(&self) -> Vec<(MessageRecipient, String)> {
+        std::mem::take(&mut self.messages.lock().unwrap())
+    }
+}
+
+impl StateroomContext for MockContext {
+    fn send_message(&self, recipient: impl Into<MessageRecipient>, message: &str) {
+        self.messages
+            .lock()
+            .unwrap()
+            .push((recipient.into(), message.to_string()));
+    }
+
+    fn send_binary(&self, recipient: impl Into<MessageRecipient>, message: &[u8]) {
+        self.binaries
+            .lock()
+            .unwrap()
+            .push((recipient.into(), message.to_vec()));
+    }
+
+    fn set_timer(&self, ms_delay: u32) {
+        self.timers.lock().unwrap().push(ms_delay);
+    }
+}
+
+/// Builds the WebAssembly text of a guest module declaring the given API
+/// version, importing every function provided by the host. `body` supplies the module's event handler exports, and may use
+/// memory above address 1024 as scratch space; `jam_malloc` always returns 1024.
+pub fn guest_wat(api_version: u8, body: &str) -> String {
+    format!(
+        r#"(module
+            (import "env" "send_message" (func $send_message (param i32 i32 i32)))
+            (import "env" "send_binary" (func $send_binary (param i32 i32 i32)))
+            (import "env" "send_message_to_clients" (func $send_message_to_clients (param i32 i32 i32 i32)))
+            (import "env" "send_binary_to_clients" (func $send_binary_to_clients (param i32 i32 i32 i32)))
+            (import "env" "set_timer" (func $set_timer (param i32)))
+            (import "env" "jam_assert" (func $jam_assert (param i32 i32 i32)))
+            (import "env" "now_rfc3339" (func $now_rfc3339 (param i32 i32) (result i32)))
+            (import "env" "membership_seq" (func $membership_seq (result i64)))
+            (import "env" "membership_since" (func $membership_since (param i64 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "random_get" (func $random_get (param i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (global (export "JAMSOCKET_API_VERSION") i32 (i32.const 16))
+            (global (export "JAMSOCKET_API_PROTOCOL") i32 (i32.const 20))
+            (data (i32.const 16) "\{:02x}\00\00\00\00\00\00\00")
+            (func (export "initialize") (param i32 i32))
+            (func (export "jam_malloc") (param i32) (result i32) (i32.const 1024))
+            (func (export "jam_free") (param i32 i32))
+            {}
+        )"#,
+        api_version, body
+    )
+}
+
+pub fn guest_module(engine: &Engine, api_version: u8, body: &str) -> Module {
+    Module::new(engine, guest_wat(api_version, body)).unwrap()
+}
@@ -1,9 +1,20 @@
-use crate::WasmRuntimeError;
+use crate::{
+    membership_log::{MembershipLog, DEFAULT_MEMBERSHIP_LOG_SIZE},
+    WasmHostConfig, WasmRuntimeError,
+};
 use anyhow::Result;
 use byteorder::{LittleEndian, ReadBytesExt};
-use stateroom::{ClientId, MessageRecipient, StateroomContext, StateroomService};
-use std::{borrow::BorrowMut, sync::Arc};
-use wasmtime::{Caller, Engine, Extern, Instance, Linker, Memory, Module, Store, TypedFunc, Val};
+use rand_chacha::{rand_core::SeedableRng, ChaCha20Rng};
+use stateroom::{
+    AcceptedMessages, ClientId, ConnectionMetadata, DisconnectReason, MessageRecipient,
+    StateroomContext, StateroomService,
+};
+use std::{borrow::BorrowMut, sync::Arc, time::SystemTime};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use wasmtime::{
+    Caller, Engine, Extern, Instance, Linker, Memory, Module, Store, StoreLimits,
+    StoreLimitsBuilder, Trap, TypedFunc, Val,
+};
 use wasmtime_wasi::sync::WasiCtxBuilder;
 use wasmtime_wasi::WasiCtx;
 
@@ -15,29 +26,97 @@ const EXT_FN_BINARY: &str = "binary";
 const EXT_FN_MESSAGE: &str = "message";
 const EXT_FN_SEND_MESSAGE: &str = "send_message";
 const EXT_FN_SEND_BINARY: &str = "send_binary";
+const EXT_FN_SEND_MESSAGE_TO_CLIENTS: &str = "send_message_to_clients";
+const EXT_FN_SEND_BINARY_TO_CLIENTS: &str = "send_binary_to_clients";
 const EXT_FN_SET_TIMER: &str = "set_timer";
+const EXT_FN_ASSERT: &str = "jam_assert";
+const EXT_FN_NOW_RFC3339: &str = "now_rfc3339";
+const EXT_FN_MEMBERSHIP_SEQ: &str = "membership_seq";
+const EXT_FN_MEMBERSHIP_SINCE: &str = "membership_since";
 const EXT_FN_TIMER: &str = "timer";
 const EXT_FN_INITIALIZE: &str = "initialize";
 const EXT_FN_MALLOC: &str = "jam_malloc";
 const EXT_FN_FREE: &str = "jam_free";
+const EXT_FN_SNAPSHOT: &str = "jam_snapshot";
+const EXT_FN_RESTORE: &str = "jam_restore";
+const EXT_FN_ACCEPTED_MESSAGES: &str = "jam_accepted_messages";
 const EXT_JAMSOCKET_VERSION: &str = "JAMSOCKET_API_VERSION";
 const EXT_JAMSOCKET_PROTOCOL: &str = "JAMSOCKET_API_PROTOCOL";
 
-const EXPECTED_API_VERSION: i32 = 1;
+/// The oldest API version a module may declare and still be loaded.
+const MIN_API_VERSION: i32 = 1;
+/// The newest API version understood by the host.
+///
+/// - Version 2: `disconnect` takes a reason code as a second argument.
+/// - Version 3: `connect` takes a pointer and length of the client's
+///   [ConnectionMetadata], in the encoding of [ConnectionMetadata::encode].
+///   When the metadata is empty, both are zero.
+/// - Version 4: `connect` returns 0 to accept the client, or any other value
+///   to reject it.
+/// - Version 5: the module exports `jam_snapshot` and `jam_restore`.
+/// - Version 6: the module exports `jam_accepted_messages`, which returns the
+///   types of message it accepts, encoded as by [AcceptedMessages::encode_u32].
+///   For older modules, [WasmHostConfig::accepted_messages] is used instead.
+const CURRENT_API_VERSION: i32 = 6;
 const EXPECTED_PROTOCOL_VERSION: i32 = 0;
 
+/// The `connect` export, whose signature depends on the module's API version.
+#[derive(Clone, Copy)]
+enum ConnectFn {
+    /// API versions 1 and 2: `connect(client)`.
+    Legacy(TypedFunc<u32, ()>),
+    /// API version 3: `connect(client, metadata_ptr, metadata_len)`.
+    WithMetadata(TypedFunc<(u32, u32, u32), ()>),
+    /// API version 4 and later: `connect(client, metadata_ptr, metadata_len) -> status`.
+    Authorizing(TypedFunc<(u32, u32, u32), i32>),
+}
+
+/// The `disconnect` export, whose signature depends on the module's API version.
+enum DisconnectFn {
+    /// API version 1: `disconnect(client)`.
+    Legacy(TypedFunc<u32, ()>),
+    /// API version 2 and later: `disconnect(client, reason)`.
+    WithReason(TypedFunc<(u32, u32), ()>),
+}
+
+/// Data owned by the store of each [WasmHost].
+struct HostState {
+    wasi: WasiCtx,
+    limits: StoreLimits,
+    membership: MembershipLog,
+}
+
+/// Tops up the store's fuel to `fuel_per_call`, if a limit is set.
+fn refuel(store: &mut Store<HostState>, fuel_per_call: Option<u64>) -> Result<()> {
+    if let Some(fuel_per_call) = fuel_per_call {
+        // `consume_fuel` fails when no fuel remains.
+        let remaining = store.consume_fuel(0).unwrap_or(0);
+        if remaining < fuel_per_call {
+            store.add_fuel(fuel_per_call - remaining)?;
+        }
+    }
+
+    Ok(())
+}
+
 /// Hosts a [stateroom::StateroomService] implemented by a WebAssembly module.
 pub struct WasmHost {
-    store: Store<WasiCtx>,
+    store: Store<HostState>,
     memory: Memory,
+    fuel_per_call: Option<u64>,
+    accepted_messages: AcceptedMessages,
 
     fn_malloc: TypedFunc<u32, u32>,
     fn_free: TypedFunc<(u32, u32), ()>,
     fn_message: TypedFunc<(u32, u32, u32), ()>,
     fn_binary: TypedFunc<(u32, u32, u32), ()>,
-    fn_connect: TypedFunc<u32, ()>,
-    fn_disconnect: TypedFunc<u32, ()>,
+    fn_connect: ConnectFn,
+    fn_disconnect: DisconnectFn,
     fn_timer: TypedFunc<(), ()>,
+    /// `jam_snapshot(out) -> status`, for modules declaring API version 5 or later.
+    fn_snapshot: Option<TypedFunc<u32, i32>>,
+    /// `jam_restore(ptr, len) -> status`, for modules declaring API version 5 or later.
+    fn_restore: Option<TypedFunc<(u32, u32), i32>>,
 }
 
 impl WasmHost {
@@ -51,7 +130,51 @@ impl WasmHost {
         Ok((pt, len))
     }
 
+    fn refuel(&mut self) -> Result<()> {
+        refuel(&mut self.store, self.fuel_per_call)
+    }
+
+    /// Copies `data` into the module's memory for the duration of `call`,
+    /// which is passed a pointer and length. Empty data is passed as (0, 0).
+    fn with_data<R>(
+        &mut self,
+        data: &[u8],
+        call: impl FnOnce(&mut Store<HostState>, u32, u32) -> Result<R>,
+    ) -> Result<R> {
+        if data.is_empty() {
+            return call(&mut self.store, 0, 0);
+        }
+
+        let (pt, len) = self.put_data(data)?;
+        let result = call(&mut self.store, pt, len)?;
+        self.fn_free.call(&mut self.store, (pt, len))?;
+
+        Ok(result)
+    }
+
+    /// Calls the module's `connect`, returning its status code (0 if the
+    /// client was accepted).
+    fn try_connect(&mut self, client: ClientId, metadata: &ConnectionMetadata) -> Result<i32> {
+        self.refuel()?;
+        let client = client.into();
+
+        match self.fn_connect {
+            ConnectFn::Legacy(f) => {
+                f.call(&mut self.store, client)?;
+                Ok(0)
+            }
+            ConnectFn::WithMetadata(f) => self.with_data(&metadata.encode(), |store, pt, len| {
+                f.call(store, (client, pt, len))?;
+                Ok(0)
+            }),
+            ConnectFn::Authorizing(f) => self.with_data(&metadata.encode(), |store, pt, len| {
+                Ok(f.call(store, (client, pt, len))?)
+            }),
+        }
+    }
+
     fn try_message(&mut self, client: ClientId, message: &str) -> Result<()> {
+        self.refuel()?;
         let (pt, len) = self.put_data(message.as_bytes())?;
 
         self.fn_message
@@ -62,11 +185,81 @@ impl WasmHost {
         Ok(())
     }
 
+    /// Calls the module's `jam_snapshot`, returning `None` if the module does not
+    /// support snapshots.
+    fn try_snapshot(&mut self) -> Result<Option<Vec<u8>>> {
+        let fn_snapshot = match self.fn_snapshot {
+            Some(f) => f,
+            None => return Ok(None),
+        };
+
+        self.refuel()?;
+        // The module writes the location and length of the snapshot here.
+        let out = self.fn_malloc.call(&mut self.store, 8)?;
+        let snapshot = self.read_snapshot(fn_snapshot, out);
+        let freed = self.fn_free.call(&mut self.store, (out, 8));
+
+        let snapshot = snapshot?;
+        freed?;
+        Ok(snapshot)
+    }
+
+    /// Calls `jam_snapshot` with the 8-byte buffer at `out`, and copies out
+    /// the snapshot it points to.
+    fn read_snapshot(
+        &mut self,
+        fn_snapshot: TypedFunc<u32, i32>,
+        out: u32,
+    ) -> Result<Option<Vec<u8>>> {
+        if fn_snapshot.call(&mut self.store, out)? != 0 {
+            return Ok(None);
+        }
+
+        let mut location = [0; 8];
+        self.memory
+            .read(&mut self.store, out as usize, &mut location)?;
+        let mut location = &location[..];
+        let pt = location.read_u32::<LittleEndian>()?;
+        let len = location.read_u32::<LittleEndian>()?;
+
+        // The module reports the length, so check it against the module's
+        // memory before allocating a buffer for it.
+        pt.checked_add(len)
+            .filter(|&end| end as usize <= self.memory.data_size(&self.store))
+            .ok_or_else(|| {
+                anyhow::anyhow!("Snapshot at {} of length {} is outside of memory.", pt, len)
+            })?;
+
+        let mut snapshot = vec![0; len as usize];
+        if len > 0 {
+            self.memory
+                .read(&mut self.store, pt as usize, &mut snapshot)?;
+            self.fn_free.call(&mut self.store, (pt, len))?;
+        }
+
+        Ok(Some(snapshot))
+    }
+
+    /// Calls the module's `jam_restore`, returning its status code (0 if the
+    /// snapshot was restored).
+    fn try_restore(&mut self, snapshot: &[u8]) -> Result<Option<i32>> {
+        let fn_restore = match self.fn_restore {
+            Some(f) => f,
+            None => return Ok(None),
+        };
+
+        self.refuel()?;
+        self.with_data(snapshot, |store, pt, len| {
+            Ok(Some(fn_restore.call(store, (pt, len))?))
+        })
+    }
+
     fn try_binary(&mut self, client: ClientId, message: &[u8]) -> Result<()> {
+        self.refuel()?;
         let (pt, len) = self.put_data(message)?;
 
         self.fn_binary
-            .call(&mut self.store, (client.into(), pt as u32, len))?;
+            .call(&mut self.store, (client.into(), pt, len))?;
 
         self.fn_free.call(&mut self.store, (pt, len))?;
 
@@ -82,19 +275,60 @@ impl StateroomService for WasmHost {
     }
 
     fn connect(&mut self, client: ClientId) {
-        if let Err(error) = self.fn_connect.call(&mut self.store, client.into()) {
-            tracing::error!(?error, "Error calling `connect` on wasm host");
+        let _ = self.connect_with_metadata(client, &ConnectionMetadata::default());
+    }
+
+    fn connect_with_metadata(
+        &mut self,
+        client: ClientId,
+        metadata: &ConnectionMetadata,
+    ) -> Result<(), String> {
+        let result = match self.try_connect(client, metadata) {
+            Ok(0) => Ok(()),
+            Ok(status) => Err(format!("Rejected by service (status {}).", status)),
+            // A module that fails while deciding whether to accept a client
+            // does not accept it. The error may include a backtrace of the
+            // module, so it is logged rather than sent to the client.
+            Err(error) => {
+                tracing::error!(?error, "Error calling `connect` on wasm host");
+                Err("Connect failed.".to_string())
+            }
+        };
+
+        if result.is_ok() {
+            self.store.data_mut().membership.record(client, true);
         }
+
+        result
     }
 
     fn disconnect(&mut self, client: ClientId) {
-        if let Err(error) = self.fn_disconnect.call(&mut self.store, client.into()) {
+        self.disconnect_with_reason(client, DisconnectReason::default());
+    }
+
+    fn disconnect_with_reason(&mut self, client: ClientId, reason: DisconnectReason) {
+        self.store.data_mut().membership.record(client, false);
+
+        let result = self.refuel().and_then(|()| {
+            Ok(match &self.fn_disconnect {
+                DisconnectFn::Legacy(f) => f.call(&mut self.store, client.into()),
+                DisconnectFn::WithReason(f) => {
+                    f.call(&mut self.store, (client.into(), reason.encode_u32()))
+                }
+            }?)
+        });
+
+        if let Err(error) = result {
             tracing::error!(?error, "Error calling `disconnect` on wasm host");
         };
     }
 
     fn timer(&mut self) {
-        if let Err(error) = self.fn_timer.call(&mut self.store, ()) {
+        let result = self
+            .refuel()
+            .and_then(|()| Ok(self.fn_timer.call(&mut self.store, ())?));
+
+        if let Err(error) = result {
             tracing::error!(?error, "Error calling `timer` on wasm host");
         };
     }
@@ -104,13 +338,39 @@ impl StateroomService for WasmHost {
             tracing::error!(?error, "Error calling `binary` on wasm host");
         };
     }
+
+    fn snapshot(&mut self) -> Option<Vec<u8>> {
+        match self.try_snapshot() {
+            Ok(snapshot) => snapshot,
+            Err(error) => {
+                tracing::error!(?error, "Error calling `jam_snapshot` on wasm host");
+                None
+            }
+        }
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), String> {
+        match self.try_restore(snapshot) {
+            Ok(Some(0)) => Ok(()),
+            Ok(Some(status)) => Err(format!("Snapshot rejected by service (status {}).", status)),
+            Ok(None) => Err("Module does not support restoring snapshots.".to_string()),
+            Err(error) => {
+                tracing::error!(?error, "Error calling `jam_restore` on wasm host");
+                Err(format!("Error restoring snapshot: {:?}", error))
+            }
+        }
+    }
+
+    fn accepted_messages(&self) -> AcceptedMessages {
+        self.accepted_messages
+    }
 }
 
 #[inline]
-fn get_memory<T>(caller: &mut Caller<'_, T>) -> Memory {
+fn get_memory<T>(caller: &mut Caller<'_, T>) -> Result<Memory> {
     match caller.get_export(EXT_MEMORY) {
-        Some(Extern::Memory(mem)) => mem,
-        _ => panic!(),
+        Some(Extern::Memory(mem)) => Ok(mem),
+        _ => Err(WasmRuntimeError::CouldNotImportMemory.into()),
     }
 }
 
@@ -121,7 +381,7 @@ fn get_string<'a, T>(
     start: u32,
     len: u32,
 ) -> Result<&'a str> {
-    let data = get_u8_vec(caller, memory, start, len);
+    let data = get_u8_vec(caller, memory, start, len)?;
     std::str::from_utf8(data).map_err(|e| e.into())
 }
 
@@ -131,14 +391,29 @@ fn get_u8_vec<'a, T>(
     memory: &'a Memory,
     start: u32,
     len: u32,
-) -> &'a [u8] {
-    let data = memory
-        .data(caller)
-        .get(start as usize..(start + len) as usize);
-    match data {
-        Some(data) => data,
-        None => panic!(),
-    }
+) -> Result<&'a [u8]> {
+    // The module provides the pointer and length, so they are not trusted.
+    start
+        .checked_add(len)
+        .and_then(|end| memory.data(caller).get(start as usize..end as usize))
+        .ok_or_else(|| anyhow::anyhow!("Data at {} of length {} is outside of memory.", start, len))
+}
+
+/// Reads a list of `count` client IDs, stored as little-endian `u32`s.
+#[inline]
+fn get_client_list<T>(
+    caller: &Caller<'_, T>,
+    memory: &Memory,
+    start: u32,
+    count: u32,
+) -> Result<MessageRecipient> {
+    #[allow(clippy::cast_possible_truncation)]
+    let len = count
+        .checked_mul(std::mem::size_of::<u32>() as u32)
+        .ok_or_else(|| anyhow::anyhow!("List of {} clients is too long.", count))?;
+    Ok(MessageRecipient::decode_clients(get_u8_vec(
+        caller, memory, start, len,
+    )?))
 }
 
 pub fn get_global<T>(
@@ -175,11 +450,46 @@ impl WasmHost {
         engine: &Engine,
         context: &Arc<impl StateroomContext + Send + Sync + 'static>,
     ) -> Result<Self> {
-        let wasi = WasiCtxBuilder::new().inherit_stdio().build();
+        Self::new_with_config(room_id, module, engine, context, &WasmHostConfig::default())
+    }
+
+    pub fn new_with_config(
+        room_id: &str,
+        module: &Module,
+        engine: &Engine,
+        context: &Arc<impl StateroomContext + Send + Sync + 'static>,
+        config: &WasmHostConfig,
+    ) -> Result<Self> {
+        let mut wasi = WasiCtxBuilder::new().inherit_stdio().build();
+        if let Some(seed) = config.seed {
+            wasi.random = Box::new(ChaCha20Rng::seed_from_u64(seed));
+        }
+
+        let mut limits = StoreLimitsBuilder::new();
+        if let Some(max_memory_bytes) = config.max_memory_bytes {
+            limits = limits.memory_size(max_memory_bytes);
+        }
+
+        let mut store = Store::new(
+            engine,
+            HostState {
+                wasi,
+                limits: limits.build(),
+                membership: MembershipLog::new(
+                    config
+                        .membership_log_size
+                        .unwrap_or(DEFAULT_MEMBERSHIP_LOG_SIZE),
+                ),
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+
+        if config.fuel_per_call.is_some() && store.fuel_consumed().is_none() {
+            return Err(WasmRuntimeError::FuelNotEnabled.into());
+        }
 
-        let mut store = Store::new(engine, wasi);
         let mut linker = Linker::new(engine);
-        wasmtime_wasi::add_to_linker(&mut linker, |s| s)?;
+        wasmtime_wasi::add_to_linker(&mut linker, |s: &mut HostState| &mut s.wasi)?;
 
         {
             #[allow(clippy::redundant_clone)]
@@ -187,8 +497,8 @@ impl WasmHost {
             linker.func_wrap(
                 ENV,
                 EXT_FN_SEND_MESSAGE,
-                move |mut caller: Caller<'_, WasiCtx>, client: i32, start: u32, len: u32| {
-                    let memory = get_memory(&mut caller);
+                move |mut caller: Caller<'_, HostState>, client: i32, start: u32, len: u32| {
+                    let memory = get_memory(&mut caller)?;
                     let message = get_string(&caller, &memory, start, len)?;
 
                     context.send_message(MessageRecipient::decode_i32(client), message);
@@ -204,9 +514,9 @@ impl WasmHost {
             linker.func_wrap(
                 ENV,
                 EXT_FN_SEND_BINARY,
-                move |mut caller: Caller<'_, WasiCtx>, client: i32, start: u32, len: u32| {
-                    let memory = get_memory(&mut caller);
-                    let message = get_u8_vec(&caller, &memory, start, len);
+                move |mut caller: Caller<'_, HostState>, client: i32, start: u32, len: u32| {
+                    let memory = get_memory(&mut caller)?;
+                    let message = get_u8_vec(&caller, &memory, start, len)?;
 
                     context.send_binary(MessageRecipient::decode_i32(client), message);
 
@@ -215,13 +525,57 @@ impl WasmHost {
             )?;
         }
 
+        {
+            #[allow(clippy::redundant_clone)]
+            let context = context.clone();
+            linker.func_wrap(
+                ENV,
+                EXT_FN_SEND_MESSAGE_TO_CLIENTS,
+                move |mut caller: Caller<'_, HostState>,
+                      clients_start: u32,
+                      clients_count: u32,
+                      start: u32,
+                      len: u32| {
+                    let memory = get_memory(&mut caller)?;
+                    let clients = get_client_list(&caller, &memory, clients_start, clients_count)?;
+                    let message = get_string(&caller, &memory, start, len)?;
+
+                    context.send_message(clients, message);
+
+                    Ok(())
+                },
+            )?;
+        }
+
+        {
+            #[allow(clippy::redundant_clone)]
+            let context = context.clone();
+            linker.func_wrap(
+                ENV,
+                EXT_FN_SEND_BINARY_TO_CLIENTS,
+                move |mut caller: Caller<'_, HostState>,
+                      clients_start: u32,
+                      clients_count: u32,
+                      start: u32,
+                      len: u32| {
+                    let memory = get_memory(&mut caller)?;
+                    let clients = get_client_list(&caller, &memory, clients_start, clients_count)?;
+                    let message = get_u8_vec(&caller, &memory, start, len)?;
+
+                    context.send_binary(clients, message);
+
+                    Ok(())
+                },
+            )?;
+        }
+
         {
             #[allow(clippy::redundant_clone)]
             let context = context.clone();
             linker.func_wrap(
                 ENV,
                 EXT_FN_SET_TIMER,
-                move |_: Caller<'_, WasiCtx>, duration_ms: u32| {
+                move |_: Caller<'_, HostState>, duration_ms: u32| {
                     context.set_timer(duration_ms);
 
                     Ok(())
@@ -229,6 +583,89 @@ impl WasmHost {
             )?;
         }
 
+        {
+            let room_id = room_id.to_string();
+            linker.func_wrap(
+                ENV,
+                EXT_FN_ASSERT,
+                move |mut caller: Caller<'_, HostState>, condition: i32, start: u32, len: u32| {
+                    if condition != 0 {
+                        return Ok(());
+                    }
+
+                    let memory = get_memory(&mut caller)?;
+                    let message = get_string(&caller, &memory, start, len)?;
+                    tracing::error!(%room_id, %message, "Assertion failed in WebAssembly module");
+
+                    Err(Trap::new(format!("Assertion failed: {}", message)))
+                },
+            )?;
+        }
+
+        {
+            let clock = config.clock.clone();
+            linker.func_wrap(
+                ENV,
+                EXT_FN_NOW_RFC3339,
+                move |mut caller: Caller<'_, HostState>, start: u32, max: u32| {
+                    let now = clock
+                        .as_ref()
+                        .map_or_else(SystemTime::now, |clock| clock.now());
+                    let formatted = OffsetDateTime::from(now)
+                        .format(&Rfc3339)
+                        .map_err(|error| Trap::new(format!("Could not format time: {}", error)))?;
+
+                    // If the buffer is too small, nothing is written, and the
+                    // returned length tells the module how much space it needs.
+                    if formatted.len() <= max as usize {
+                        let memory = get_memory(&mut caller)?;
+                        memory
+                            .write(&mut caller, start as usize, formatted.as_bytes())
+                            .map_err(|error| Trap::new(error.to_string()))?;
+                    }
+
+                    #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+                    Ok(formatted.len() as i32)
+                },
+            )?;
+        }
+
+        linker.func_wrap(
+            ENV,
+            EXT_FN_MEMBERSHIP_SEQ,
+            |caller: Caller<'_, HostState>| {
+                #[allow(clippy::cast_possible_wrap)]
+                Ok(caller.data().membership.seq() as i64)
+            },
+        )?;
+
+        linker.func_wrap(
+            ENV,
+            EXT_FN_MEMBERSHIP_SINCE,
+            |mut caller: Caller<'_, HostState>, seq: i64, start: u32, max: u32| {
+                #[allow(clippy::cast_sign_loss)]
+                let encoded: Vec<u8> = match caller.data().membership.since(seq.max(0) as u64) {
+                    Some(events) => events.flat_map(|event| event.encode()).collect(),
+                    None => return Ok(-1),
+                };
+                let count = encoded.len() / 8;
+
+                // As with `now_rfc3339`, nothing is written if the buffer is
+                // too small, and the returned count tells the module how many
+                // entries it needs space for.
+                if count <= max as usize {
+                    let memory = get_memory(&mut caller)?;
+                    memory
+                        .write(&mut caller, start as usize, &encoded)
+                        .map_err(|error| Trap::new(error.to_string()))?;
+                }
+
+                #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+                Ok(count as i32)
+            },
+        )?;
+
+        refuel(&mut store, config.fuel_per_call)?;
         let instance = linker.instantiate(&mut store, module)?;
 
         let initialize =
@@ -249,14 +686,14 @@ impl WasmHost {
             let pt = fn_malloc.call(&mut store, len)?;
 
             memory.write(&mut store, pt as usize, room_id)?;
+            refuel(&mut store, config.fuel_per_call)?;
             initialize.call(&mut store, (pt, len))?;
 
             fn_free.call(&mut store, (pt, len))?;
         }
 
-        if get_global(&mut store, &mut memory, &instance, EXT_JAMSOCKET_VERSION)?
-            != EXPECTED_API_VERSION
-        {
+        let api_version = get_global(&mut store, &mut memory, &instance, EXT_JAMSOCKET_VERSION)?;
+        if !(MIN_API_VERSION..=CURRENT_API_VERSION).contains(&api_version) {
             return Err(WasmRuntimeError::InvalidApiVersion.into());
         }
 
@@ -266,9 +703,27 @@ impl WasmHost {
             return Err(WasmRuntimeError::InvalidProtocolVersion.into());
         }
 
-        let fn_connect = instance.get_typed_func::<u32, (), _>(&mut store, EXT_FN_CONNECT)?;
+        let fn_connect = if api_version >= 4 {
+            ConnectFn::Authorizing(
+                instance.get_typed_func::<(u32, u32, u32), i32, _>(&mut store, EXT_FN_CONNECT)?,
+            )
+        } else if api_version >= 3 {
+            ConnectFn::WithMetadata(
+                instance.get_typed_func::<(u32, u32, u32), (), _>(&mut store, EXT_FN_CONNECT)?,
+            )
+        } else {
+            ConnectFn::Legacy(instance.get_typed_func::<u32, (), _>(&mut store, EXT_FN_CONNECT)?)
+        };
 
-        let fn_disconnect = instance.get_typed_func::<u32, (), _>(&mut store, EXT_FN_DISCONNECT)?;
+        let fn_disconnect = if api_version >= 2 {
+            DisconnectFn::WithReason(
+                instance.get_typed_func::<(u32, u32), (), _>(&mut store, EXT_FN_DISCONNECT)?,
+            )
+        } else {
+            DisconnectFn::Legacy(
+                instance.get_typed_func::<u32, (), _>(&mut store, EXT_FN_DISCONNECT)?,
+            )
+        };
 
         let fn_timer = instance.get_typed_func::<(), (), _>(&mut store, EXT_FN_TIMER)?;
 
@@ -278,9 +733,31 @@ impl WasmHost {
         let fn_binary =
             instance.get_typed_func::<(u32, u32, u32), (), _>(&mut store, EXT_FN_BINARY)?;
 
+        let (fn_snapshot, fn_restore) = if api_version >= 5 {
+            (
+                Some(instance.get_typed_func::<u32, i32, _>(&mut store, EXT_FN_SNAPSHOT)?),
+                Some(instance.get_typed_func::<(u32, u32), i32, _>(&mut store, EXT_FN_RESTORE)?),
+            )
+        } else {
+            (None, None)
+        };
+
+        // Asked once, after `initialize`, since the host cannot call into the
+        // module from `accepted_messages(&self)`.
+        let accepted_messages = if api_version >= 6 {
+            let fn_accepted_messages =
+                instance.get_typed_func::<(), u32, _>(&mut store, EXT_FN_ACCEPTED_MESSAGES)?;
+            refuel(&mut store, config.fuel_per_call)?;
+            AcceptedMessages::decode_u32(fn_accepted_messages.call(&mut store, ())?)
+        } else {
+            config.accepted_messages
+        };
+
         Ok(WasmHost {
             store,
             memory,
+            fuel_per_call: config.fuel_per_call,
+            accepted_messages,
             fn_malloc,
             fn_free,
             fn_message,
@@ -288,6 +765,618 @@ impl WasmHost {
             fn_connect,
             fn_disconnect,
             fn_timer,
+            fn_snapshot,
+            fn_restore,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{guest_module, MockContext};
+
+    const HANDLERS_V1: &str = r#"
+        (func (export "connect") (param i32))
+        (func (export "disconnect") (param i32)
+            (i32.store8 (i32.const 100) (i32.const 120))
+            (call $send_message (i32.const 0) (i32.const 100) (i32.const 1)))
+        (func (export "timer"))
+        (func (export "message") (param i32 i32 i32))
+        (func (export "binary") (param i32 i32 i32))
+    "#;
+
+    /// Responds to a disconnect by broadcasting the reason code as a digit.
+    const HANDLERS_V2: &str = r#"
+        (func (export "connect") (param i32))
+        (func (export "disconnect") (param i32 i32)
+            (i32.store8 (i32.const 100) (i32.add (i32.const 48) (local.get 1)))
+            (call $send_message (i32.const 0) (i32.const 100) (i32.const 1)))
+        (func (export "timer"))
+        (func (export "message") (param i32 i32 i32))
+        (func (export "binary") (param i32 i32 i32))
+    "#;
+
+    #[test]
+    fn test_disconnect_reason() {
+        let engine = Engine::default();
+        let module = guest_module(&engine, 2, HANDLERS_V2);
+        let context = MockContext::default();
+        let mut host = WasmHost::new("", &module, &engine, &Arc::new(context.clone())).unwrap();
+
+        host.disconnect_with_reason(1.into(), DisconnectReason::ServerKick);
+        host.disconnect_with_reason(2.into(), DisconnectReason::ClientClosed);
+
+        assert_eq!(
+            vec![
+                (MessageRecipient::Broadcast, "2".to_string()),
+                (MessageRecipient::Broadcast, "0".to_string()),
+            ],
+            context.take_messages()
+        );
+    }
+
+    #[test]
+    fn test_legacy_disconnect() {
+        let engine = Engine::default();
+        let module = guest_module(&engine, 1, HANDLERS_V1);
+        let context = MockContext::default();
+        let mut host = WasmHost::new("", &module, &engine, &Arc::new(context.clone())).unwrap();
+
+        host.disconnect_with_reason(1.into(), DisconnectReason::ServerKick);
+
+        assert_eq!(
+            vec![(MessageRecipient::Broadcast, "x".to_string())],
+            context.take_messages()
+        );
+    }
+
+    #[test]
+    fn test_unsupported_api_version() {
+        let engine = Engine::default();
+        let module = guest_module(&engine, 99, HANDLERS_V2);
+        let context = Arc::new(MockContext::default());
+
+        assert!(WasmHost::new("", &module, &engine, &context).is_err());
+    }
+
+    #[test]
+    fn test_send_to_client_list() {
+        let engine = Engine::default();
+        let module = guest_module(
+            &engine,
+            2,
+            r#"
+            (data (i32.const 100) "\03\00\00\00\05\00\00\00hi")
+            (func (export "connect") (param i32)
+                (call $send_message_to_clients (i32.const 100) (i32.const 2) (i32.const 108) (i32.const 2))
+                (call $send_message_to_clients (i32.const 100) (i32.const 0) (i32.const 108) (i32.const 2)))
+            (func (export "disconnect") (param i32 i32))
+            (func (export "timer"))
+            (func (export "message") (param i32 i32 i32))
+            (func (export "binary") (param i32 i32 i32))
+            "#,
+        );
+        let context = MockContext::default();
+        let mut host = WasmHost::new("", &module, &engine, &Arc::new(context.clone())).unwrap();
+
+        host.connect(1.into());
+
+        assert_eq!(
+            vec![
+                (
+                    MessageRecipient::Clients(vec![3.into(), 5.into()]),
+                    "hi".to_string()
+                ),
+                (MessageRecipient::Clients(vec![]), "hi".to_string()),
+            ],
+            context.take_messages()
+        );
+    }
+
+    #[test]
+    fn test_out_of_bounds_client_list_traps() {
+        // `message` passes its first argument as the number of clients, and
+        // `binary` passes a message that runs past the end of the address space.
+        let engine = Engine::default();
+        let module = guest_module(
+            &engine,
+            2,
+            r#"
+            (func (export "connect") (param i32))
+            (func (export "disconnect") (param i32 i32))
+            (func (export "timer"))
+            (func (export "message") (param i32 i32 i32)
+                (call $send_message_to_clients (i32.const 100) (local.get 0) (i32.const 108) (i32.const 2)))
+            (func (export "binary") (param i32 i32 i32)
+                (call $send_binary (i32.const 0) (i32.const -16) (i32.const 32)))
+            "#,
+        );
+        let context = MockContext::default();
+        let mut host = WasmHost::new("", &module, &engine, &Arc::new(context.clone())).unwrap();
+
+        // The byte length of the list overflows a u32; these trap rather than
+        // panicking in the host.
+        assert!(host.try_message(0x4000_0001.into(), "").is_err());
+        assert!(host.try_message(0x3FFF_FFFF.into(), "").is_err());
+        assert!(host.try_binary(1.into(), b"").is_err());
+
+        assert!(context.take_messages().is_empty());
+        assert!(context.binaries.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_send_to_others() {
+        let engine = Engine::default();
+        // Relays each message to everyone but its sender, as text and binary.
+        let module = guest_module(
+            &engine,
+            4,
+            r#"
+            (func (export "connect") (param i32 i32 i32) (result i32) (i32.const 0))
+            (func (export "disconnect") (param i32 i32))
+            (func (export "timer"))
+            (func (export "message") (param i32 i32 i32)
+                (call $send_message
+                    (i32.sub (i32.const 0) (local.get 0)) (local.get 1) (local.get 2))
+                (call $send_binary
+                    (i32.sub (i32.const 0) (local.get 0)) (local.get 1) (local.get 2)))
+            (func (export "binary") (param i32 i32 i32))
+            "#,
+        );
+        let context = MockContext::default();
+        let mut host = WasmHost::new("", &module, &engine, &Arc::new(context.clone())).unwrap();
+
+        host.message(7.into(), "hello");
+
+        assert_eq!(
+            vec![(
+                MessageRecipient::EveryoneExcept(7.into()),
+                "hello".to_string()
+            )],
+            context.take_messages()
+        );
+        assert_eq!(
+            vec![(
+                MessageRecipient::EveryoneExcept(7.into()),
+                b"hello".to_vec()
+            )],
+            *context.binaries.lock().unwrap()
+        );
+    }
+
+    /// Spins forever when sent a message, grows memory when a client connects,
+    /// and broadcasts "ok" on a timer.
+    const HANDLERS_GREEDY: &str = r#"
+        (func (export "connect") (param i32)
+            (if (i32.eq (memory.grow (i32.const 100)) (i32.const -1))
+                (then unreachable)))
+        (func (export "disconnect") (param i32 i32))
+        (func (export "timer")
+            (i32.store16 (i32.const 100) (i32.const 0x6b6f))
+            (call $send_message (i32.const 0) (i32.const 100) (i32.const 2)))
+        (func (export "message") (param i32 i32 i32)
+            (loop $spin (br $spin)))
+        (func (export "binary") (param i32 i32 i32))
+    "#;
+
+    #[test]
+    fn test_fuel_limit() {
+        let config = WasmHostConfig::default().with_fuel_per_call(100_000);
+        let engine = Engine::new(&config.engine_config()).unwrap();
+        let module = guest_module(&engine, 2, HANDLERS_GREEDY);
+        let context = MockContext::default();
+        let mut host =
+            WasmHost::new_with_config("", &module, &engine, &Arc::new(context.clone()), &config)
+                .unwrap();
+
+        let error = host.try_message(1.into(), "spin").unwrap_err();
+        assert!(format!("{:?}", error).contains("fuel"));
+
+        // The host remains usable, with its fuel replenished.
+        host.timer();
+        host.timer();
+        assert_eq!(
+            vec![
+                (MessageRecipient::Broadcast, "ok".to_string()),
+                (MessageRecipient::Broadcast, "ok".to_string()),
+            ],
+            context.take_messages()
+        );
+    }
+
+    #[test]
+    fn test_fuel_requires_engine_support() {
+        let config = WasmHostConfig::default().with_fuel_per_call(100_000);
+        let engine = Engine::default();
+        let module = guest_module(&engine, 2, HANDLERS_GREEDY);
+        let context = Arc::new(MockContext::default());
+
+        assert!(WasmHost::new_with_config("", &module, &engine, &context, &config).is_err());
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let engine = Engine::default();
+        let module = guest_module(&engine, 2, HANDLERS_GREEDY);
+        let context = MockContext::default();
+
+        let mut unlimited =
+            WasmHost::new("", &module, &engine, &Arc::new(context.clone())).unwrap();
+        assert!(unlimited
+            .try_connect(1.into(), &ConnectionMetadata::default())
+            .is_ok());
+
+        let config = WasmHostConfig::default().with_max_memory_bytes(1 << 20);
+        let mut limited =
+            WasmHost::new_with_config("", &module, &engine, &Arc::new(context.clone()), &config)
+                .unwrap();
+        assert!(limited
+            .try_connect(1.into(), &ConnectionMetadata::default())
+            .is_err());
+
+        // The failed call is logged and the host remains usable.
+        limited.connect(1.into());
+        limited.timer();
+        assert_eq!(
+            vec![(MessageRecipient::Broadcast, "ok".to_string())],
+            context.take_messages()
+        );
+    }
+
+    #[test]
+    fn test_failed_assertion() {
+        // Asserts that the first byte of each message is nonzero, then echoes it.
+        const HANDLERS_ASSERT: &str = r#"
+            (data (i32.const 200) "first byte is zero")
+            (func (export "connect") (param i32))
+            (func (export "disconnect") (param i32 i32))
+            (func (export "timer"))
+            (func (export "message") (param i32 i32 i32)
+                (call $jam_assert (i32.load8_u (local.get 1)) (i32.const 200) (i32.const 18))
+                (call $send_message (i32.const 0) (local.get 1) (local.get 2)))
+            (func (export "binary") (param i32 i32 i32))
+        "#;
+
+        let engine = Engine::default();
+        let module = guest_module(&engine, 2, HANDLERS_ASSERT);
+        let context = MockContext::default();
+        let mut host = WasmHost::new("room", &module, &engine, &Arc::new(context.clone())).unwrap();
+
+        let error = host.try_message(1.into(), "\0").unwrap_err();
+        assert!(format!("{:?}", error).contains("Assertion failed: first byte is zero"));
+        assert!(context.take_messages().is_empty());
+
+        // The room keeps working after the aborted call.
+        host.message(1.into(), "\0");
+        host.message(1.into(), "ok");
+        assert_eq!(
+            vec![(MessageRecipient::Broadcast, "ok".to_string())],
+            context.take_messages()
+        );
+    }
+
+    /// Echoes the client's connection metadata back to it as a binary message.
+    const HANDLERS_V3: &str = r#"
+        (func (export "connect") (param i32 i32 i32)
+            (call $send_binary (local.get 0) (local.get 1) (local.get 2)))
+        (func (export "disconnect") (param i32 i32))
+        (func (export "timer"))
+        (func (export "message") (param i32 i32 i32))
+        (func (export "binary") (param i32 i32 i32))
+    "#;
+
+    #[test]
+    fn test_connect_metadata() {
+        let engine = Engine::default();
+        let module = guest_module(&engine, 3, HANDLERS_V3);
+        let context = MockContext::default();
+        let mut host = WasmHost::new("", &module, &engine, &Arc::new(context.clone())).unwrap();
+
+        let metadata = ConnectionMetadata::default().with_param("name", "alice");
+        assert_eq!(Ok(()), host.connect_with_metadata(1.into(), &metadata));
+        host.connect(2.into());
+
+        let binaries = std::mem::take(&mut *context.binaries.lock().unwrap());
+        assert_eq!(2, binaries.len());
+        assert_eq!(MessageRecipient::Client(1.into()), binaries[0].0);
+        let echoed = ConnectionMetadata::decode(&binaries[0].1).unwrap();
+        assert_eq!(Some("alice"), echoed.get("name"));
+        assert_eq!((MessageRecipient::Client(2.into()), vec![]), binaries[1]);
+    }
+
+    #[test]
+    fn test_legacy_connect_ignores_metadata() {
+        let engine = Engine::default();
+        let module = guest_module(
+            &engine,
+            2,
+            r#"
+            (func (export "connect") (param i32)
+                (call $send_binary (local.get 0) (i32.const 0) (i32.const 0)))
+            (func (export "disconnect") (param i32 i32))
+            (func (export "timer"))
+            (func (export "message") (param i32 i32 i32))
+            (func (export "binary") (param i32 i32 i32))
+            "#,
+        );
+        let context = MockContext::default();
+        let mut host = WasmHost::new("", &module, &engine, &Arc::new(context.clone())).unwrap();
+
+        let metadata = ConnectionMetadata::default().with_param("name", "alice");
+        assert_eq!(Ok(()), host.connect_with_metadata(1.into(), &metadata));
+
+        assert_eq!(
+            vec![(MessageRecipient::Client(1.into()), vec![])],
+            *context.binaries.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_connect_rejection() {
+        // Accepts clients whose metadata is empty, and rejects all others.
+        let engine = Engine::default();
+        let module = guest_module(
+            &engine,
+            4,
+            r#"
+            (func (export "connect") (param i32 i32 i32) (result i32)
+                (local.get 2))
+            (func (export "disconnect") (param i32 i32))
+            (func (export "timer"))
+            (func (export "message") (param i32 i32 i32))
+            (func (export "binary") (param i32 i32 i32))
+            "#,
+        );
+        let context = Arc::new(MockContext::default());
+        let mut host = WasmHost::new("", &module, &engine, &context).unwrap();
+
+        assert_eq!(
+            Ok(()),
+            host.connect_with_metadata(1.into(), &ConnectionMetadata::default())
+        );
+
+        let metadata = ConnectionMetadata::default().with_param("a", "b");
+        assert!(host.connect_with_metadata(2.into(), &metadata).is_err());
+    }
+
+    #[test]
+    fn test_trapping_connect_rejects() {
+        let engine = Engine::default();
+        let module = guest_module(
+            &engine,
+            4,
+            r#"
+            (func (export "connect") (param i32 i32 i32) (result i32)
+                unreachable)
+            (func (export "disconnect") (param i32 i32))
+            (func (export "timer"))
+            (func (export "message") (param i32 i32 i32))
+            (func (export "binary") (param i32 i32 i32))
+            "#,
+        );
+        let context = Arc::new(MockContext::default());
+        let mut host = WasmHost::new("", &module, &engine, &context).unwrap();
+
+        let result = host.connect_with_metadata(1.into(), &ConnectionMetadata::default());
+        assert_eq!(Err("Connect failed.".to_string()), result);
+
+        // The rejected client is not recorded as having joined.
+        assert_eq!(0, host.store.data().membership.seq());
+    }
+
+    #[test]
+    fn test_seeded_randomness() {
+        // Sends each connecting client 16 random bytes.
+        const HANDLERS_RANDOM: &str = r#"
+            (func (export "connect") (param i32 i32 i32) (result i32)
+                (drop (call $random_get (i32.const 100) (i32.const 16)))
+                (call $send_binary (local.get 0) (i32.const 100) (i32.const 16))
+                (i32.const 0))
+            (func (export "disconnect") (param i32 i32))
+            (func (export "timer"))
+            (func (export "message") (param i32 i32 i32))
+            (func (export "binary") (param i32 i32 i32))
+        "#;
+
+        let engine = Engine::default();
+        let module = guest_module(&engine, 4, HANDLERS_RANDOM);
+
+        let random_bytes = |seed: Option<u64>| {
+            let config = WasmHostConfig {
+                seed,
+                ..WasmHostConfig::default()
+            };
+            let context = MockContext::default();
+            let mut host = WasmHost::new_with_config(
+                "",
+                &module,
+                &engine,
+                &Arc::new(context.clone()),
+                &config,
+            )
+            .unwrap();
+            host.connect(1.into());
+            host.connect(2.into());
+
+            let binaries = context.binaries.lock().unwrap();
+            binaries
+                .iter()
+                .map(|(_, bytes)| bytes.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let first = random_bytes(Some(42));
+        assert_eq!(2, first.len());
+        assert_ne!(first[0], first[1]);
+        assert_eq!(first, random_bytes(Some(42)));
+        assert_ne!(first, random_bytes(Some(43)));
+        assert_ne!(first, random_bytes(None));
+    }
+
+    #[test]
+    fn test_now_rfc3339() {
+        // Sends each connecting client the current time. A message asks for the
+        // time with a buffer too small to hold it, and sends back whatever is
+        // in the buffer afterwards.
+        const HANDLERS_TIME: &str = r#"
+            (func (export "connect") (param i32 i32 i32) (result i32)
+                (call $send_message
+                    (local.get 0)
+                    (i32.const 100)
+                    (call $now_rfc3339 (i32.const 100) (i32.const 64)))
+                (i32.const 0))
+            (func (export "disconnect") (param i32 i32))
+            (func (export "timer"))
+            (func (export "message") (param i32 i32 i32)
+                (call $send_message
+                    (local.get 0)
+                    (i32.const 200)
+                    (call $now_rfc3339 (i32.const 200) (i32.const 4))))
+            (func (export "binary") (param i32 i32 i32))
+        "#;
+
+        let engine = Engine::default();
+        let module = guest_module(&engine, 4, HANDLERS_TIME);
+        let now = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        let config = WasmHostConfig::default().with_clock(crate::FixedClock(now));
+
+        let context = MockContext::default();
+        let mut host =
+            WasmHost::new_with_config("", &module, &engine, &Arc::new(context.clone()), &config)
+                .unwrap();
+
+        host.connect(1.into());
+        let messages = context.take_messages();
+        assert_eq!(
+            vec![(
+                MessageRecipient::Client(1.into()),
+                "2023-11-14T22:13:20Z".to_string()
+            )],
+            messages
+        );
+        let parsed = OffsetDateTime::parse(&messages[0].1, &Rfc3339).unwrap();
+        assert_eq!(OffsetDateTime::from(now), parsed);
+
+        host.message(1.into(), "");
+        assert_eq!(
+            vec![(MessageRecipient::Client(1.into()), "\0".repeat(20))],
+            context.take_messages()
+        );
+    }
+
+    #[test]
+    fn test_membership_since_checkpoint() {
+        // `message` stores the current sequence number at address 200 as a
+        // checkpoint, and `timer` sends the changes since it as binary.
+        const HANDLERS_MEMBERSHIP: &str = r#"
+            (func (export "connect") (param i32 i32 i32) (result i32) (i32.const 0))
+            (func (export "disconnect") (param i32 i32))
+            (func (export "message") (param i32 i32 i32)
+                (i64.store (i32.const 200) (call $membership_seq)))
+            (func (export "timer")
+                (local $count i32)
+                (local.set $count
+                    (call $membership_since (i64.load (i32.const 200)) (i32.const 300) (i32.const 16)))
+                (if (i32.lt_s (local.get $count) (i32.const 0))
+                    (then (local.set $count (i32.const 0))))
+                (call $send_binary
+                    (i32.const 0) (i32.const 300) (i32.mul (local.get $count) (i32.const 8))))
+            (func (export "binary") (param i32 i32 i32))
+        "#;
+
+        let engine = Engine::default();
+        let module = guest_module(&engine, 4, HANDLERS_MEMBERSHIP);
+        let context = MockContext::default();
+        let mut host = WasmHost::new("", &module, &engine, &Arc::new(context.clone())).unwrap();
+
+        host.connect(1.into());
+        host.connect(2.into());
+        host.message(1.into(), "");
+
+        host.connect(3.into());
+        host.disconnect(1.into());
+        host.connect(4.into());
+        host.disconnect(3.into());
+        host.timer();
+
+        // Pairs of client ID and kind, where 1 is a connect and 0 a disconnect.
+        let expected: Vec<u8> = [3u32, 1, 1, 0, 4, 1, 3, 0]
+            .iter()
+            .flat_map(|n| n.to_le_bytes())
+            .collect();
+        assert_eq!(
+            vec![(MessageRecipient::Broadcast, expected)],
+            std::mem::take(&mut *context.binaries.lock().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_snapshot_length_outside_memory() {
+        // Reports a snapshot longer than the module's memory.
+        const HANDLERS_BAD_SNAPSHOT: &str = r#"
+            (func (export "connect") (param i32 i32 i32) (result i32) (i32.const 0))
+            (func (export "disconnect") (param i32 i32))
+            (func (export "timer"))
+            (func (export "message") (param i32 i32 i32))
+            (func (export "binary") (param i32 i32 i32))
+            (func (export "jam_snapshot") (param i32) (result i32)
+                (i32.store (local.get 0) (i32.const 16))
+                (i32.store (i32.add (local.get 0) (i32.const 4)) (i32.const -32))
+                (i32.const 0))
+            (func (export "jam_restore") (param i32 i32) (result i32) (i32.const 1))
+        "#;
+
+        let engine = Engine::default();
+        let module = guest_module(&engine, 5, HANDLERS_BAD_SNAPSHOT);
+        let mut host =
+            WasmHost::new("", &module, &engine, &Arc::new(MockContext::default())).unwrap();
+
+        assert!(host.try_snapshot().is_err());
+        assert_eq!(None, host.snapshot());
+    }
+
+    #[test]
+    fn test_snapshot_unsupported_before_v5() {
+        let engine = Engine::default();
+        let module = guest_module(&engine, 2, HANDLERS_V2);
+        let mut host =
+            WasmHost::new("", &module, &engine, &Arc::new(MockContext::default())).unwrap();
+
+        assert_eq!(None, host.snapshot());
+        assert!(host.restore(&[]).is_err());
+    }
+
+    #[test]
+    fn test_module_declares_accepted_messages() {
+        // Declares that it accepts only text, whatever the config says.
+        const HANDLERS_TEXT_ONLY: &str = r#"
+            (func (export "connect") (param i32 i32 i32) (result i32) (i32.const 0))
+            (func (export "disconnect") (param i32 i32))
+            (func (export "timer"))
+            (func (export "message") (param i32 i32 i32))
+            (func (export "binary") (param i32 i32 i32))
+            (func (export "jam_snapshot") (param i32) (result i32) (i32.const 1))
+            (func (export "jam_restore") (param i32 i32) (result i32) (i32.const 1))
+            (func (export "jam_accepted_messages") (result i32) (i32.const 1))
+        "#;
+
+        let engine = Engine::default();
+        let context = Arc::new(MockContext::default());
+        let config = WasmHostConfig::default().with_accepted_messages(AcceptedMessages::ALL);
+
+        let module = guest_module(&engine, 6, HANDLERS_TEXT_ONLY);
+        let host = WasmHost::new_with_config("", &module, &engine, &context, &config).unwrap();
+        assert_eq!(AcceptedMessages::TEXT_ONLY, host.accepted_messages());
+
+        // Older modules cannot declare it, so the config is used.
+        let config = config.with_accepted_messages(AcceptedMessages::BINARY_ONLY);
+        let module = guest_module(&engine, 5, HANDLERS_TEXT_ONLY);
+        let host = WasmHost::new_with_config("", &module, &engine, &context, &config).unwrap();
+        assert_eq!(AcceptedMessages::BINARY_ONLY, host.accepted_messages());
+
+        // Version 6 modules must export it.
+        let module = guest_module(&engine, 6, HANDLERS_V2);
+        assert!(WasmHost::new_with_config("", &module, &engine, &context, &config).is_err());
+    }
+}
@@ -0,0 +1,116 @@
+use crate::Clock;
+use stateroom::AcceptedMessages;
+use std::sync::Arc;
+
+/// Determines which [wasmtime::Engine] a [crate::WasmHostFactory] builds hosts with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum EngineMode {
+    /// Every host uses the factory's engine and compiled module.
+    #[default]
+    Shared,
+
+    /// Each thread that builds hosts gets its own engine, with a copy of the
+    /// module deserialized from bytes compiled once by the factory. This avoids
+    /// contention on a single engine when hosts are created and run on many
+    /// threads at once, at the cost of one engine and one copy of the module's
+    /// code per thread.
+    PerThread,
+}
+
+/// Settings applied to each [crate::WasmHost] instance, including the resource
+/// limits placed on the guest module.
+#[derive(Clone, Debug, Default)]
+pub struct WasmHostConfig {
+    /// The amount of fuel the module may consume in each call from the host
+    /// (e.g. handling one message), or `None` (default) for no limit.
+    ///
+    /// Most WebAssembly instructions consume one unit of fuel. A call that runs
+    /// out of fuel traps, and the error is logged rather than propagated. The
+    /// fuel is topped up before the next call.
+    ///
+    /// Requires an [wasmtime::Engine] with fuel consumption enabled; the engine
+    /// created by [crate::WasmHostFactory::new_with_config] has this set when
+    /// a limit is provided.
+    pub fuel_per_call: Option<u64>,
+
+    /// The maximum size of the module's linear memory, in bytes, or `None`
+    /// (default) for no limit. Attempts to grow memory past this size fail.
+    pub max_memory_bytes: Option<usize>,
+
+    /// Seeds the random number generator that the module reads from (through
+    /// WASI's `random_get`), so that rooms created with the same seed see the
+    /// same random values. If `None` (default), the operating system's source
+    /// of randomness is used.
+    pub seed: Option<u64>,
+
+    /// Whether hosts share the factory's engine (default) or use one engine per
+    /// thread. See [EngineMode].
+    pub engine_mode: EngineMode,
+
+    /// The clock that the module reads the time from (through `now_rfc3339`),
+    /// or `None` (default) for the system clock.
+    pub clock: Option<Arc<dyn Clock>>,
+
+    /// How many of the most recent connects and disconnects the host keeps for
+    /// the module to read back (through `membership_since`), or `None`
+    /// (default) for 1024.
+    pub membership_log_size: Option<usize>,
+
+    /// The types of message the module handles, for modules that do not
+    /// declare them (through `jam_accepted_messages`, from API version 6).
+    /// Clients' messages of other types are rejected by the server without
+    /// calling the module. Defaults to [AcceptedMessages::ALL].
+    pub accepted_messages: AcceptedMessages,
+}
+
+impl WasmHostConfig {
+    #[must_use]
+    pub fn with_fuel_per_call(mut self, fuel_per_call: u64) -> Self {
+        self.fuel_per_call = Some(fuel_per_call);
+        self
+    }
+
+    #[must_use]
+    pub fn with_max_memory_bytes(mut self, max_memory_bytes: usize) -> Self {
+        self.max_memory_bytes = Some(max_memory_bytes);
+        self
+    }
+
+    #[must_use]
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    #[must_use]
+    pub fn with_engine_mode(mut self, engine_mode: EngineMode) -> Self {
+        self.engine_mode = engine_mode;
+        self
+    }
+
+    #[must_use]
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    #[must_use]
+    pub fn with_membership_log_size(mut self, membership_log_size: usize) -> Self {
+        self.membership_log_size = Some(membership_log_size);
+        self
+    }
+
+    #[must_use]
+    pub fn with_accepted_messages(mut self, accepted_messages: AcceptedMessages) -> Self {
+        self.accepted_messages = accepted_messages;
+        self
+    }
+
+    /// Returns a [wasmtime::Config] for an engine suitable for hosts with this configuration.
+    #[must_use]
+    pub fn engine_config(&self) -> wasmtime::Config {
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(self.fuel_per_call.is_some());
+        config
+    }
+}
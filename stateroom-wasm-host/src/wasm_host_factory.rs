@@ -1,18 +1,94 @@
-use crate::wasm_host::WasmHost;
-use anyhow::Result;
-use stateroom::{StateroomContext, StateroomServiceFactory};
-use std::{path::Path, sync::Arc};
+use crate::{wasm_host::WasmHost, EngineMode, WasmHostConfig};
+use anyhow::{anyhow, Result};
+use stateroom::{MessageRecipient, StateroomContext, StateroomService, StateroomServiceFactory};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock, Weak,
+    },
+};
 use wasmtime::{Engine, Module};
 
+/// Source of [WasmHostFactory::id] values.
+static NEXT_FACTORY_ID: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// Engines and modules used by factories in [EngineMode::PerThread] mode on
+    /// this thread, keyed by factory ID. Entries of factories that have been
+    /// dropped are removed the next time this thread creates an entry.
+    static THREAD_MODULES: RefCell<HashMap<u64, ThreadModule>> = RefCell::new(HashMap::new());
+}
+
+/// A thread's own copy of a factory's module.
+struct ThreadModule {
+    /// The factory's module slot, which is dropped along with the last clone
+    /// of the factory.
+    owner: Weak<RwLock<ModuleSlot>>,
+    generation: u64,
+    engine: Engine,
+    module: Module,
+}
+
+/// The module a factory currently builds hosts from.
+struct ModuleSlot {
+    /// Incremented whenever the module is replaced, so that per-thread copies
+    /// can tell when they are out of date.
+    generation: u64,
+    module: Arc<Module>,
+    /// The compiled module as bytes, from which per-thread copies are
+    /// deserialized. Computed the first time it is needed.
+    serialized: Option<Arc<Vec<u8>>>,
+}
+
+impl ModuleSlot {
+    fn new(module: Arc<Module>) -> Self {
+        ModuleSlot {
+            generation: 0,
+            module,
+            serialized: None,
+        }
+    }
+}
+
 /// Loads and caches a WebAssembly module such that a [WasmHost] instance can be
 /// created from it.
 ///
 /// This struct is cheaply cloneable, so it can be used to create multiple instances
-/// of the same module.
+/// of the same module. Clones share the module, so a module swapped in with
+/// [WasmHostFactory::reload] is used by every clone.
+///
+/// With [EngineMode::PerThread], each thread that builds hosts creates its own
+/// engine the first time it does so, and keeps it (along with its copy of the
+/// module) until the thread exits or, once the factory and its clones have been
+/// dropped, the thread next creates an engine or module copy for any factory.
 #[derive(Clone)]
 pub struct WasmHostFactory {
+    /// Identifies this factory and its clones in [THREAD_MODULES].
+    id: u64,
     engine: Arc<Engine>,
-    module: Arc<Module>,
+    module: Arc<RwLock<ModuleSlot>>,
+    /// The file the module was loaded from, if any, which
+    /// [StateroomServiceFactory::refresh] reloads.
+    wasm_file: Option<PathBuf>,
+    config: WasmHostConfig,
+}
+
+/// A context that discards everything, used to instantiate modules for validation.
+struct DiscardContext;
+
+impl StateroomContext for DiscardContext {
+    fn send_message(&self, _: impl Into<MessageRecipient>, _: &str) {}
+
+    fn send_binary(&self, _: impl Into<MessageRecipient>, _: &[u8]) {}
+
+    fn set_timer(&self, _: u32) {}
+}
+
+fn poisoned<T>(_: T) -> anyhow::Error {
+    anyhow!("Module lock was poisoned.")
 }
 
 impl<T: StateroomContext + Send + Sync + 'static> StateroomServiceFactory<T> for WasmHostFactory {
@@ -20,12 +96,40 @@ impl<T: StateroomContext + Send + Sync + 'static> StateroomServiceFactory<T> for
     type Error = anyhow::Error;
 
     fn build(&self, room_id: &str, context: T) -> Result<Self::Service, Self::Error> {
-        WasmHost::new(
-            room_id,
-            self.module.as_ref(),
-            self.engine.as_ref(),
-            &Arc::new(context),
-        )
+        match self.config.engine_mode {
+            EngineMode::Shared => {
+                let module = self.module()?;
+
+                WasmHost::new_with_config(
+                    room_id,
+                    module.as_ref(),
+                    self.engine.as_ref(),
+                    &Arc::new(context),
+                    &self.config,
+                )
+            }
+            EngineMode::PerThread => {
+                let (engine, module) = self.thread_module()?;
+
+                WasmHost::new_with_config(
+                    room_id,
+                    &module,
+                    &engine,
+                    &Arc::new(context),
+                    &self.config,
+                )
+            }
+        }
+    }
+
+    /// Reloads the module from the file it was loaded from, as
+    /// [WasmHostFactory::reload] does. Does nothing for a factory created from
+    /// an already-compiled module.
+    fn refresh(&self) -> Result<(), Self::Error> {
+        match &self.wasm_file {
+            Some(wasm_file) => self.reload(wasm_file),
+            None => Ok(()),
+        }
     }
 }
 
@@ -34,18 +138,369 @@ impl WasmHostFactory {
     where
         P: AsRef<Path>,
     {
-        let engine = Engine::default();
+        Self::new_with_config(wasm_file, WasmHostConfig::default())
+    }
+
+    /// Load a module with an engine suited to `config`, and apply `config` to
+    /// every [WasmHost] built by this factory.
+    pub fn new_with_config<P>(wasm_file: P, config: WasmHostConfig) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let engine = Engine::new(&config.engine_config())?;
         tracing::info!(wasm_file=?wasm_file.as_ref(), "Loading WebAssembly module");
-        let module = Module::from_file(&engine, wasm_file)?;
+        let module = Module::from_file(&engine, &wasm_file)?;
 
         Ok(WasmHostFactory {
+            id: NEXT_FACTORY_ID.fetch_add(1, Ordering::Relaxed),
             engine: Arc::new(engine),
-            module: Arc::new(module),
+            module: Arc::new(RwLock::new(ModuleSlot::new(Arc::new(module)))),
+            wasm_file: Some(wasm_file.as_ref().to_path_buf()),
+            config,
         })
     }
 
     #[must_use]
     pub fn new_with_shared_module(engine: Arc<Engine>, module: Arc<Module>) -> Self {
-        WasmHostFactory { engine, module }
+        WasmHostFactory {
+            id: NEXT_FACTORY_ID.fetch_add(1, Ordering::Relaxed),
+            engine,
+            module: Arc::new(RwLock::new(ModuleSlot::new(module))),
+            wasm_file: None,
+            config: WasmHostConfig::default(),
+        }
+    }
+
+    /// Apply `config` to every [WasmHost] built by this factory. If it sets a
+    /// fuel limit, the factory's engine must have fuel consumption enabled (see
+    /// [WasmHostConfig::engine_config]), or building a host will fail.
+    #[must_use]
+    pub fn with_config(mut self, config: WasmHostConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    fn module(&self) -> Result<Arc<Module>> {
+        Ok(self.module.read().map_err(poisoned)?.module.clone())
+    }
+
+    /// Returns the current module's generation and its serialized form,
+    /// serializing it if this has not been done yet.
+    fn serialized_module(&self) -> Result<(u64, Arc<Vec<u8>>)> {
+        {
+            let slot = self.module.read().map_err(poisoned)?;
+            if let Some(serialized) = &slot.serialized {
+                return Ok((slot.generation, serialized.clone()));
+            }
+        }
+
+        let mut slot = self.module.write().map_err(poisoned)?;
+        let serialized = match &slot.serialized {
+            Some(serialized) => serialized.clone(),
+            None => Arc::new(slot.module.serialize()?),
+        };
+        slot.serialized = Some(serialized.clone());
+
+        Ok((slot.generation, serialized))
+    }
+
+    /// Returns this thread's engine and copy of the current module, creating
+    /// them if this thread has none or its copy is out of date.
+    fn thread_module(&self) -> Result<(Engine, Module)> {
+        let current_generation = self.module.read().map_err(poisoned)?.generation;
+
+        THREAD_MODULES.with(|modules| {
+            let mut modules = modules.borrow_mut();
+
+            if let Some(thread_module) = modules.get(&self.id) {
+                if thread_module.generation == current_generation {
+                    return Ok((thread_module.engine.clone(), thread_module.module.clone()));
+                }
+            }
+
+            modules.retain(|_, thread_module| thread_module.owner.strong_count() > 0);
+
+            let (generation, serialized) = self.serialized_module()?;
+            let engine = match modules.get(&self.id) {
+                Some(thread_module) => thread_module.engine.clone(),
+                None => {
+                    tracing::info!(factory = self.id, "Creating engine for thread");
+                    Engine::new(&self.config.engine_config())?
+                }
+            };
+
+            // Safety: the bytes were produced by `Module::serialize` on a
+            // module compiled by this factory, and have not been modified.
+            let module = unsafe { Module::deserialize(&engine, serialized.as_slice())? };
+
+            modules.insert(
+                self.id,
+                ThreadModule {
+                    owner: Arc::downgrade(&self.module),
+                    generation,
+                    engine: engine.clone(),
+                    module: module.clone(),
+                },
+            );
+
+            Ok((engine, module))
+        })
+    }
+
+    /// Build a host for `room_id`, as [StateroomServiceFactory::build] does, and
+    /// restore its state from `snapshot`, which was returned by
+    /// [stateroom::StateroomService::snapshot] on a host running a compatible module.
+    pub fn build_from_snapshot<T: StateroomContext + Send + Sync + 'static>(
+        &self,
+        room_id: &str,
+        context: T,
+        snapshot: &[u8],
+    ) -> Result<WasmHost> {
+        let mut host = StateroomServiceFactory::<T>::build(self, room_id, context)?;
+        host.restore(snapshot).map_err(anyhow::Error::msg)?;
+
+        Ok(host)
+    }
+
+    /// Compile the module in `wasm_file` with this factory's engine and, if it
+    /// is valid, use it in place of the current module.
+    ///
+    /// Only rooms built after the reload use the new module; existing rooms
+    /// keep running the module they were created with, along with their state.
+    /// See [WasmHostFactory::replace_module] for how the module is validated.
+    pub fn reload<P>(&self, wasm_file: P) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        tracing::info!(wasm_file=?wasm_file.as_ref(), "Reloading WebAssembly module");
+        let module = Module::from_file(&self.engine, wasm_file)?;
+
+        self.replace_module(module)
+    }
+
+    /// Use `module`, which must have been compiled with this factory's engine,
+    /// in place of the current module for rooms built from now on.
+    ///
+    /// The module is first validated by instantiating and initializing it
+    /// (with an empty room ID and a context that discards messages), which
+    /// checks its imports, exports, and API and protocol versions. If that
+    /// fails, the error is returned and the current module is kept.
+    pub fn replace_module(&self, module: Module) -> Result<()> {
+        WasmHost::new_with_config(
+            "",
+            &module,
+            &self.engine,
+            &Arc::new(DiscardContext),
+            &self.config,
+        )?;
+
+        let mut slot = self.module.write().map_err(poisoned)?;
+        slot.generation += 1;
+        slot.module = Arc::new(module);
+        slot.serialized = None;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{guest_wat, MockContext};
+    use std::path::PathBuf;
+
+    /// Writes a guest module that broadcasts `message` when a client connects.
+    fn write_module(name: &str, api_version: u8, message: &str) -> PathBuf {
+        let body = format!(
+            r#"
+            (data (i32.const 100) "{}")
+            (func (export "connect") (param i32 i32 i32) (result i32)
+                (call $send_message (i32.const 0) (i32.const 100) (i32.const {}))
+                (i32.const 0))
+            (func (export "disconnect") (param i32 i32))
+            (func (export "timer"))
+            (func (export "message") (param i32 i32 i32))
+            (func (export "binary") (param i32 i32 i32))
+            "#,
+            message,
+            message.len()
+        );
+
+        let path = std::env::temp_dir().join(format!(
+            "stateroom-wasm-host-{}-{}.wat",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, guest_wat(api_version, &body)).unwrap();
+        path
+    }
+
+    fn connect_message(factory: &WasmHostFactory) -> Vec<(MessageRecipient, String)> {
+        let context = MockContext::default();
+        let mut host = factory.build("", context.clone()).unwrap();
+        host.connect(1.into());
+        context.take_messages()
+    }
+
+    #[test]
+    fn test_reload() {
+        let old = write_module("reload-old", 4, "old");
+        let new = write_module("reload-new", 4, "new");
+        let invalid = write_module("reload-invalid", 99, "invalid");
+
+        let factory = WasmHostFactory::new(&old).unwrap();
+        let clone = factory.clone();
+
+        let context = MockContext::default();
+        let mut existing = factory.build("", context.clone()).unwrap();
+
+        factory.reload(&new).unwrap();
+        let expected = vec![(MessageRecipient::Broadcast, "new".to_string())];
+        assert_eq!(expected, connect_message(&factory));
+        assert_eq!(expected, connect_message(&clone));
+
+        // A module that fails validation is not swapped in.
+        assert!(factory.reload(&invalid).is_err());
+        assert_eq!(expected, connect_message(&factory));
+
+        // Rooms built before the reload keep the old module.
+        existing.connect(1.into());
+        assert_eq!(
+            vec![(MessageRecipient::Broadcast, "old".to_string())],
+            context.take_messages()
+        );
+
+        // Refreshing reloads the file the factory was created from.
+        std::fs::copy(&new, &old).unwrap();
+        StateroomServiceFactory::<MockContext>::refresh(&factory).unwrap();
+        assert_eq!(expected, connect_message(&clone));
+
+        for path in [old, new, invalid] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_engine_per_thread() {
+        let old = write_module("per-thread-old", 4, "old");
+        let new = write_module("per-thread-new", 4, "new");
+
+        let config = WasmHostConfig::default().with_engine_mode(EngineMode::PerThread);
+        let factory = WasmHostFactory::new_with_config(&old, config).unwrap();
+
+        let (engine, _) = factory.thread_module().unwrap();
+        let (same_engine, _) = factory.thread_module().unwrap();
+        assert!(Engine::same(&engine, &same_engine));
+        assert!(!Engine::same(&engine, &factory.engine));
+
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let factory = factory.clone();
+                std::thread::spawn(move || {
+                    let (thread_engine, _) = factory.thread_module().unwrap();
+                    (connect_message(&factory), thread_engine)
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            let (messages, thread_engine) = handle.join().unwrap();
+            assert_eq!(
+                vec![(MessageRecipient::Broadcast, "old".to_string())],
+                messages
+            );
+            assert!(!Engine::same(&engine, &thread_engine));
+        }
+
+        // A reload replaces this thread's copy of the module, but keeps its engine.
+        factory.reload(&new).unwrap();
+        assert_eq!(
+            vec![(MessageRecipient::Broadcast, "new".to_string())],
+            connect_message(&factory)
+        );
+        let (reloaded_engine, _) = factory.thread_module().unwrap();
+        assert!(Engine::same(&engine, &reloaded_engine));
+
+        for path in [old, new] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_thread_modules_of_dropped_factories_freed() {
+        let path = write_module("per-thread-dropped", 4, "hi");
+        let config = WasmHostConfig::default().with_engine_mode(EngineMode::PerThread);
+
+        let dropped = WasmHostFactory::new_with_config(&path, config.clone()).unwrap();
+        let dropped_clone = dropped.clone();
+        dropped.thread_module().unwrap();
+        drop(dropped);
+        drop(dropped_clone);
+
+        let factory = WasmHostFactory::new_with_config(&path, config).unwrap();
+        factory.thread_module().unwrap();
+
+        let ids: Vec<u64> =
+            THREAD_MODULES.with(|modules| modules.borrow().keys().copied().collect());
+        assert_eq!(vec![factory.id], ids);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_build_from_snapshot() {
+        // Counts messages, broadcasting the count after each one.
+        let body = r#"
+            (global $count (mut i32) (i32.const 0))
+            (func (export "connect") (param i32 i32 i32) (result i32) (i32.const 0))
+            (func (export "disconnect") (param i32 i32))
+            (func (export "timer"))
+            (func (export "message") (param i32 i32 i32)
+                (global.set $count (i32.add (global.get $count) (i32.const 1)))
+                (i32.store (i32.const 2000) (global.get $count))
+                (call $send_binary (i32.const 0) (i32.const 2000) (i32.const 4)))
+            (func (export "binary") (param i32 i32 i32))
+            (func (export "jam_snapshot") (param i32) (result i32)
+                (i32.store (i32.const 2048) (global.get $count))
+                (i32.store (local.get 0) (i32.const 2048))
+                (i32.store (i32.add (local.get 0) (i32.const 4)) (i32.const 4))
+                (i32.const 0))
+            (func (export "jam_restore") (param i32 i32) (result i32)
+                (if (i32.ne (local.get 1) (i32.const 4))
+                    (then (return (i32.const 1))))
+                (global.set $count (i32.load (local.get 0)))
+                (i32.const 0))
+        "#;
+
+        let path = std::env::temp_dir().join(format!(
+            "stateroom-wasm-host-snapshot-{}.wat",
+            std::process::id()
+        ));
+        std::fs::write(&path, guest_wat(5, body)).unwrap();
+        let factory = WasmHostFactory::new(&path).unwrap();
+
+        let mut host = factory.build("", MockContext::default()).unwrap();
+        for _ in 0..3 {
+            host.message(1.into(), "count");
+        }
+        let snapshot = host.snapshot().unwrap();
+        assert_eq!(vec![3, 0, 0, 0], snapshot);
+
+        let context = MockContext::default();
+        let mut restored = factory
+            .build_from_snapshot("", context.clone(), &snapshot)
+            .unwrap();
+        restored.message(1.into(), "count");
+        assert_eq!(
+            vec![(MessageRecipient::Broadcast, vec![4, 0, 0, 0])],
+            *context.binaries.lock().unwrap()
+        );
+
+        // The module rejects snapshots of the wrong length.
+        assert!(factory
+            .build_from_snapshot("", MockContext::default(), &[1, 2])
+            .is_err());
+
+        std::fs::remove_file(path).unwrap();
     }
 }
@@ -1,2 +1,57 @@
 pub use stateroom_wasm_macro::stateroom_wasm;
 pub mod prelude;
+
+#[cfg(target_arch = "wasm32")]
+mod ffi {
+    extern "C" {
+        pub fn jam_assert(condition: i32, message: u32, message_len: u32);
+        pub fn now_rfc3339(out: u32, max: u32) -> i32;
+    }
+}
+
+/// Asserts that `condition` holds. If it does not, the host logs `message`
+/// along with the room ID and aborts the current call into the module. The
+/// room itself keeps running, and later calls are handled as usual.
+///
+/// When not compiled to WebAssembly, this panics with `message` instead.
+pub fn jam_assert(condition: bool, message: &str) {
+    #[cfg(target_arch = "wasm32")]
+    unsafe {
+        ffi::jam_assert(
+            i32::from(condition),
+            message.as_ptr() as u32,
+            message.len() as u32,
+        );
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    assert!(condition, "{}", message);
+}
+
+/// Returns the current UTC time as an RFC 3339 string, such as
+/// `2023-11-14T22:13:20Z`, as reported by the host.
+///
+/// When not compiled to WebAssembly, this formats the system time instead.
+#[must_use]
+pub fn now_rfc3339() -> String {
+    #[cfg(target_arch = "wasm32")]
+    {
+        let mut buffer = vec![0; 40];
+        loop {
+            #[allow(clippy::cast_sign_loss)]
+            let len = unsafe { ffi::now_rfc3339(buffer.as_mut_ptr() as u32, buffer.len() as u32) }
+                as usize;
+            if len <= buffer.len() {
+                buffer.truncate(len);
+                return String::from_utf8(buffer)
+                    .expect("Host returned a timestamp that is not UTF-8.");
+            }
+            buffer.resize(len, 0);
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    time::OffsetDateTime::now_utc()
+        .format(&time::format_description::well_known::Rfc3339)
+        .expect("Could not format the current time.")
+}
@@ -1,6 +1,8 @@
 /// Re-exports useful items from `stateroom` and `stateroom_wasm_macro`.
+pub use crate::{jam_assert, now_rfc3339};
 pub use stateroom::{
-    ClientId, MessageRecipient, SimpleStateroomService, StateroomContext, StateroomService,
-    StateroomServiceFactory, WrappedStateroomService,
+    AcceptedMessages, ClientId, ConnectionMetadata, DisconnectReason, MessageRecipient,
+    SimpleStateroomService, StateroomContext, StateroomService, StateroomServiceFactory,
+    WrappedStateroomService,
 };
 pub use stateroom_wasm_macro::stateroom_wasm;
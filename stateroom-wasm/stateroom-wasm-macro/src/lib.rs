@@ -31,17 +31,20 @@ fn stateroom_wasm_impl(item: &proc_macro2::TokenStream) -> proc_macro2::TokenStr
 
             use super::*;
             use stateroom_wasm::prelude::{
+                AcceptedMessages,
                 MessageRecipient,
                 SimpleStateroomService,
                 StateroomContext,
                 ClientId,
+                ConnectionMetadata,
+                DisconnectReason,
             };
 
             // Instance-global stateroom service.
             static mut SERVER_STATE: Option<#name> = None;
 
             #[no_mangle]
-            pub static JAMSOCKET_API_VERSION: i32 = 1;
+            pub static JAMSOCKET_API_VERSION: i32 = 6;
 
             #[no_mangle]
             pub static JAMSOCKET_API_PROTOCOL: i32 = 0;
@@ -57,21 +60,44 @@ fn stateroom_wasm_impl(item: &proc_macro2::TokenStream) -> proc_macro2::TokenStr
 
                 fn send_message(&self, recipient: impl Into<MessageRecipient>, message: &str) {
                     unsafe {
-                        ffi::send_message(
-                            recipient.into().encode_i32(),
-                            &message.as_bytes()[0] as *const u8 as u32,
-                            message.len() as u32,
-                        );
+                        match recipient.into() {
+                            MessageRecipient::Clients(clients) => ffi::send_message_to_clients(
+                                clients.as_ptr() as u32,
+                                clients.len() as u32,
+                                &message.as_bytes()[0] as *const u8 as u32,
+                                message.len() as u32,
+                            ),
+                            // A client ID too large to encode would be read as a
+                            // different recipient, so the message is dropped.
+                            recipient => if let Some(recipient) = recipient.encode_i32() {
+                                ffi::send_message(
+                                    recipient,
+                                    &message.as_bytes()[0] as *const u8 as u32,
+                                    message.len() as u32,
+                                )
+                            },
+                        }
                     }
                 }
 
                 fn send_binary(&self, recipient: impl Into<MessageRecipient>, message: &[u8]) {
                     unsafe {
-                        ffi::send_binary(
-                            recipient.into().encode_i32(),
-                            &message[0] as *const u8 as u32,
-                            message.len() as u32,
-                        );
+                        match recipient.into() {
+                            MessageRecipient::Clients(clients) => ffi::send_binary_to_clients(
+                                clients.as_ptr() as u32,
+                                clients.len() as u32,
+                                &message[0] as *const u8 as u32,
+                                message.len() as u32,
+                            ),
+                            // As in `send_message`.
+                            recipient => if let Some(recipient) = recipient.encode_i32() {
+                                ffi::send_binary(
+                                    recipient,
+                                    &message[0] as *const u8 as u32,
+                                    message.len() as u32,
+                                )
+                            },
+                        }
                     }
                 }
             }
@@ -83,6 +109,10 @@ fn stateroom_wasm_impl(item: &proc_macro2::TokenStream) -> proc_macro2::TokenStr
 
                     pub fn send_binary(client: i32, message: u32, message_len: u32);
 
+                    pub fn send_message_to_clients(clients: u32, clients_len: u32, message: u32, message_len: u32);
+
+                    pub fn send_binary_to_clients(clients: u32, clients_len: u32, message: u32, message_len: u32);
+
                     pub fn set_timer(ms_delay: u32);
                 }
             }
@@ -101,17 +131,31 @@ fn stateroom_wasm_impl(item: &proc_macro2::TokenStream) -> proc_macro2::TokenStr
             }
 
             #[no_mangle]
-            extern "C" fn connect(client_id: ClientId) {
+            extern "C" fn connect(client_id: ClientId, metadata_ptr: *const u8, metadata_len: usize) -> i32 {
+                let metadata = if metadata_len == 0 {
+                    ConnectionMetadata::default()
+                } else {
+                    let data = unsafe { std::slice::from_raw_parts(metadata_ptr, metadata_len) };
+                    ConnectionMetadata::decode(data).unwrap_or_default()
+                };
+
                 match unsafe { SERVER_STATE.as_mut() } {
-                    Some(st) => SimpleStateroomService::connect(st, client_id.into(), &GlobalStateroomContext),
-                    None => ()
+                    Some(st) => {
+                        if SimpleStateroomService::authorize(st, client_id.into(), &metadata, &GlobalStateroomContext).is_err() {
+                            return 1;
+                        }
+
+                        SimpleStateroomService::connect_with_metadata(st, client_id.into(), &metadata, &GlobalStateroomContext);
+                        0
+                    }
+                    None => 0
                 }
             }
 
             #[no_mangle]
-            extern "C" fn disconnect(client_id: ClientId) {
+            extern "C" fn disconnect(client_id: ClientId, reason: u32) {
                 match unsafe { SERVER_STATE.as_mut() } {
-                    Some(st) => SimpleStateroomService::disconnect(st, client_id.into(), &GlobalStateroomContext),
+                    Some(st) => SimpleStateroomService::disconnect_with_reason(st, client_id.into(), DisconnectReason::decode_u32(reason), &GlobalStateroomContext),
                     None => ()
                 }
             }
@@ -148,6 +192,61 @@ fn stateroom_wasm_impl(item: &proc_macro2::TokenStream) -> proc_macro2::TokenStr
                 }
             }
 
+            #[no_mangle]
+            extern "C" fn jam_snapshot(out: *mut u32) -> i32 {
+                let snapshot = match unsafe { SERVER_STATE.as_ref() } {
+                    Some(st) => SimpleStateroomService::snapshot(st),
+                    None => None,
+                };
+
+                match snapshot {
+                    Some(snapshot) => {
+                        // Copied into memory from `jam_malloc`, which the host frees with `jam_free`.
+                        let ptr = if snapshot.is_empty() {
+                            core::ptr::null_mut()
+                        } else {
+                            unsafe {
+                                let ptr = jam_malloc(snapshot.len() as u32);
+                                core::ptr::copy_nonoverlapping(snapshot.as_ptr(), ptr, snapshot.len());
+                                ptr
+                            }
+                        };
+
+                        unsafe {
+                            out.write_unaligned(ptr as u32);
+                            out.add(1).write_unaligned(snapshot.len() as u32);
+                        }
+                        0
+                    }
+                    None => 1
+                }
+            }
+
+            #[no_mangle]
+            extern "C" fn jam_restore(ptr: *const u8, len: usize) -> i32 {
+                let snapshot = if len == 0 {
+                    &[]
+                } else {
+                    unsafe { std::slice::from_raw_parts(ptr, len) }
+                };
+
+                match unsafe { SERVER_STATE.as_mut() } {
+                    Some(st) => match SimpleStateroomService::restore(st, snapshot, &GlobalStateroomContext) {
+                        Ok(()) => 0,
+                        Err(_) => 1,
+                    },
+                    None => 1
+                }
+            }
+
+            #[no_mangle]
+            extern "C" fn jam_accepted_messages() -> u32 {
+                match unsafe { SERVER_STATE.as_ref() } {
+                    Some(st) => SimpleStateroomService::accepted_messages(st).encode_u32(),
+                    None => AcceptedMessages::ALL.encode_u32(),
+                }
+            }
+
             #[no_mangle]
             pub unsafe extern "C" fn jam_malloc(size: u32) -> *mut u8 {
                 let layout = core::alloc::Layout::from_size_align_unchecked(size as usize, 0);
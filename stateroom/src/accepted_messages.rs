@@ -0,0 +1,73 @@
+/// The types of message a service handles. Messages of other types are
+/// rejected by the server before they reach the service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AcceptedMessages {
+    pub text: bool,
+    pub binary: bool,
+}
+
+impl AcceptedMessages {
+    pub const ALL: AcceptedMessages = AcceptedMessages {
+        text: true,
+        binary: true,
+    };
+
+    pub const TEXT_ONLY: AcceptedMessages = AcceptedMessages {
+        text: true,
+        binary: false,
+    };
+
+    pub const BINARY_ONLY: AcceptedMessages = AcceptedMessages {
+        text: false,
+        binary: true,
+    };
+
+    /// Encodes the accepted types as flags: 1 for text and 2 for binary.
+    #[must_use]
+    pub fn encode_u32(&self) -> u32 {
+        u32::from(self.text) | u32::from(self.binary) << 1
+    }
+
+    /// Decodes flags from [AcceptedMessages::encode_u32]. Unknown flags are ignored.
+    #[must_use]
+    pub fn decode_u32(flags: u32) -> Self {
+        AcceptedMessages {
+            text: flags & 1 != 0,
+            binary: flags & 2 != 0,
+        }
+    }
+}
+
+impl Default for AcceptedMessages {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::AcceptedMessages;
+
+    #[test]
+    fn test_encode_accepted_messages() {
+        assert_eq!(3, AcceptedMessages::ALL.encode_u32());
+        assert_eq!(1, AcceptedMessages::TEXT_ONLY.encode_u32());
+        assert_eq!(2, AcceptedMessages::BINARY_ONLY.encode_u32());
+
+        for accepted in [
+            AcceptedMessages::ALL,
+            AcceptedMessages::TEXT_ONLY,
+            AcceptedMessages::BINARY_ONLY,
+        ] {
+            assert_eq!(
+                accepted,
+                AcceptedMessages::decode_u32(accepted.encode_u32())
+            );
+        }
+
+        assert_eq!(
+            AcceptedMessages::TEXT_ONLY,
+            AcceptedMessages::decode_u32(0x101)
+        );
+    }
+}
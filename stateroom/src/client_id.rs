@@ -3,7 +3,7 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq, Hash, Ord)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
-
+#[repr(transparent)]
 pub struct ClientId(pub u32);
 
 impl From<ClientId> for u32 {
@@ -16,4 +16,4 @@ impl From<u32> for ClientId {
     fn from(u: u32) -> Self {
         ClientId(u)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,110 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, convert::TryInto};
+
+/// Information supplied by a client when it connects, such as an auth token
+/// or a display name.
+///
+/// The server fills `query` with the query parameters of the WebSocket upgrade
+/// request, so a client connecting to `/ws?name=alice` has the parameter
+/// `name` set to `alice`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ConnectionMetadata {
+    pub query: BTreeMap<String, String>,
+}
+
+impl ConnectionMetadata {
+    #[must_use]
+    pub fn with_param(mut self, key: &str, value: &str) -> Self {
+        self.query.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Returns the value of the query parameter `key`, if the client supplied it.
+    #[must_use]
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.query.get(key).map(String::as_str)
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.query.is_empty()
+    }
+
+    /// Encodes the metadata for passing to a WebAssembly module.
+    ///
+    /// Each query parameter is encoded as its key followed by its value, where
+    /// each string is a little-endian `u32` byte length followed by its UTF-8
+    /// bytes. Parameters appear in key order. Empty metadata encodes as zero bytes.
+    #[must_use]
+    pub fn encode(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+
+        for (key, value) in &self.query {
+            for part in [key, value] {
+                #[allow(clippy::cast_possible_truncation)]
+                result.extend_from_slice(&(part.len() as u32).to_le_bytes());
+                result.extend_from_slice(part.as_bytes());
+            }
+        }
+
+        result
+    }
+
+    /// Decodes metadata encoded by [ConnectionMetadata::encode], returning
+    /// `None` if the data is truncated or not valid UTF-8.
+    #[must_use]
+    pub fn decode(mut data: &[u8]) -> Option<Self> {
+        fn take_string(data: &mut &[u8]) -> Option<String> {
+            let len = u32::from_le_bytes(data.get(..4)?.try_into().ok()?) as usize;
+            let bytes = data.get(4..4 + len)?;
+            *data = &data[4 + len..];
+            String::from_utf8(bytes.to_vec()).ok()
+        }
+
+        let mut query = BTreeMap::new();
+        while !data.is_empty() {
+            let key = take_string(&mut data)?;
+            let value = take_string(&mut data)?;
+            query.insert(key, value);
+        }
+
+        Some(ConnectionMetadata { query })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        assert!(ConnectionMetadata::default().encode().is_empty());
+        assert_eq!(
+            Some(ConnectionMetadata::default()),
+            ConnectionMetadata::decode(&[])
+        );
+
+        let metadata = ConnectionMetadata::default()
+            .with_param("name", "alice")
+            .with_param("team", "")
+            .with_param("émoji", "🦦");
+
+        assert_eq!(
+            vec![4, 0, 0, 0, b'n', b'a', b'm', b'e', 5, 0, 0, 0, b'a', b'l', b'i', b'c', b'e'],
+            metadata.encode()[..17]
+        );
+        assert_eq!(
+            Some(metadata.clone()),
+            ConnectionMetadata::decode(&metadata.encode())
+        );
+
+        let encoded = metadata.encode();
+        assert_eq!(
+            None,
+            ConnectionMetadata::decode(&encoded[..encoded.len() - 1])
+        );
+        assert_eq!(None, ConnectionMetadata::decode(&encoded[..15]));
+    }
+}
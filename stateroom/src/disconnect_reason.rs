@@ -0,0 +1,80 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Represents the cause of a client's disconnection from a service.
+///
+/// Reasons are distinguished by whether the client closed the connection
+/// itself, or the server closed it on the client's behalf.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum DisconnectReason {
+    /// The client closed the connection.
+    #[default]
+    ClientClosed,
+    /// The server closed the connection because the client stopped responding
+    /// to heartbeats.
+    HeartbeatTimeout,
+    /// The server closed the connection on its own initiative.
+    ServerKick,
+    /// The service rejected the client when it connected. Services are not
+    /// notified of these disconnections, since the client never joined.
+    Rejected,
+    /// The server closed the connection because it is shutting down.
+    ServerShutdown,
+}
+
+impl DisconnectReason {
+    /// Returns `true` if the server, rather than the client, closed the connection.
+    #[must_use]
+    pub fn is_server_initiated(&self) -> bool {
+        !matches!(self, Self::ClientClosed)
+    }
+
+    #[must_use]
+    pub fn encode_u32(&self) -> u32 {
+        match self {
+            Self::ClientClosed => 0,
+            Self::HeartbeatTimeout => 1,
+            Self::ServerKick => 2,
+            Self::Rejected => 3,
+            Self::ServerShutdown => 4,
+        }
+    }
+
+    /// Decodes a reason code. Unknown codes are treated as [DisconnectReason::ClientClosed].
+    #[must_use]
+    pub fn decode_u32(code: u32) -> Self {
+        match code {
+            1 => Self::HeartbeatTimeout,
+            2 => Self::ServerKick,
+            3 => Self::Rejected,
+            4 => Self::ServerShutdown,
+            _ => Self::ClientClosed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::DisconnectReason;
+
+    #[test]
+    fn test_encode_decode() {
+        for reason in [
+            DisconnectReason::ClientClosed,
+            DisconnectReason::HeartbeatTimeout,
+            DisconnectReason::ServerKick,
+            DisconnectReason::Rejected,
+            DisconnectReason::ServerShutdown,
+        ] {
+            assert_eq!(reason, DisconnectReason::decode_u32(reason.encode_u32()));
+        }
+
+        assert_eq!(
+            DisconnectReason::ClientClosed,
+            DisconnectReason::decode_u32(99)
+        );
+        assert!(!DisconnectReason::ClientClosed.is_server_initiated());
+        assert!(DisconnectReason::ServerKick.is_server_initiated());
+    }
+}
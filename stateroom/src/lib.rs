@@ -33,7 +33,7 @@
 //!                      &username));
 //!
 //!         // Alert all other connected users to the new user.
-//!         ctx.send_message(MessageRecipient::Broadcast,
+//!         ctx.send_message_to_others(client,
 //!             &format!("{} has joined the chat", &username));
 //!     }
 //!
@@ -62,12 +62,18 @@
 //!     }
 //! }
 
+pub use accepted_messages::AcceptedMessages;
 pub use client_id::ClientId;
+pub use connection_metadata::ConnectionMetadata;
+pub use disconnect_reason::DisconnectReason;
 pub use message_recipient::MessageRecipient;
 pub use messages::{MessageFromProcess, MessagePayload, MessageToProcess};
 use std::convert::Infallible;
 
+mod accepted_messages;
 mod client_id;
+mod connection_metadata;
+mod disconnect_reason;
 mod message_recipient;
 mod messages;
 
@@ -85,6 +91,17 @@ pub trait StateroomContext {
     /// See [StateroomContext::send_message] for details on the semantics of `recipient`.
     fn send_binary(&self, recipient: impl Into<MessageRecipient>, message: &[u8]);
 
+    /// Sends a message to every connected user except `sender`, such as to relay a
+    /// message from `sender` to the rest of the room.
+    fn send_message_to_others(&self, sender: ClientId, message: &str) {
+        self.send_message(MessageRecipient::EveryoneExcept(sender), message);
+    }
+
+    /// Sends a binary message to every connected user except `sender`.
+    fn send_binary_to_others(&self, sender: ClientId, message: &[u8]) {
+        self.send_binary(MessageRecipient::EveryoneExcept(sender), message);
+    }
+
     /// Sets a timer to wake up the service in the given number of milliseconds by invoking `timer()`.
     ///
     /// Each instance of a service can only have one (or zero) timer outstanding at any time; if this
@@ -106,10 +123,45 @@ pub trait SimpleStateroomService {
     /// Called each time a client connects to the service.
     fn connect(&mut self, client: ClientId, context: &impl StateroomContext) {}
 
+    /// Called when a client connects, before [SimpleStateroomService::connect_with_metadata],
+    /// to decide whether to accept the client. Returning an error rejects the client: its
+    /// connection is closed with the error as the reason, and neither `connect` nor
+    /// `disconnect` is called for it. By default, every client is accepted.
+    fn authorize(
+        &mut self,
+        client: ClientId,
+        metadata: &ConnectionMetadata,
+        context: &impl StateroomContext,
+    ) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Called in place of [SimpleStateroomService::connect] with metadata supplied by the
+    /// client. By default, it ignores the metadata and calls `connect`.
+    fn connect_with_metadata(
+        &mut self,
+        client: ClientId,
+        metadata: &ConnectionMetadata,
+        context: &impl StateroomContext,
+    ) {
+        self.connect(client, context);
+    }
+
     /// Called each time a client disconnects from the service, unless that disconnection
     /// will cause the service to be destroyed.
     fn disconnect(&mut self, client: ClientId, context: &impl StateroomContext) {}
 
+    /// Called in place of [SimpleStateroomService::disconnect] with the reason for the
+    /// disconnection. By default, it ignores the reason and calls `disconnect`.
+    fn disconnect_with_reason(
+        &mut self,
+        client: ClientId,
+        reason: DisconnectReason,
+        context: &impl StateroomContext,
+    ) {
+        self.disconnect(client, context);
+    }
+
     /// Called each time a client sends a text message to the service.
     fn message(&mut self, client: ClientId, message: &str, context: &impl StateroomContext) {}
 
@@ -119,6 +171,26 @@ pub trait SimpleStateroomService {
     /// Called when [StateroomContext::set_timer] has been called on this service's context,
     /// after the provided duration.
     fn timer(&mut self, context: &impl StateroomContext) {}
+
+    /// Returns the service's state, in a form that [SimpleStateroomService::restore] can
+    /// read, so that it can be persisted or moved to another host. Returns `None`
+    /// (default) if the service does not support snapshots.
+    fn snapshot(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Replaces the service's state with one returned by [SimpleStateroomService::snapshot],
+    /// returning an error if it cannot be restored. Called after `new`, before any client
+    /// connects. By default, snapshots are not supported and an error is returned.
+    fn restore(&mut self, snapshot: &[u8], context: &impl StateroomContext) -> Result<(), String> {
+        Err("Service does not support restoring snapshots.".to_string())
+    }
+
+    /// The types of message the service handles. Clients' messages of other types are
+    /// rejected instead of being passed to `message` or `binary`. By default, both are accepted.
+    fn accepted_messages(&self) -> AcceptedMessages {
+        AcceptedMessages::ALL
+    }
 }
 
 /// The host interface to a Stateroom service. Implementations should instead implement the trait
@@ -128,10 +200,29 @@ pub trait StateroomService {
     /// Called each time a client connects to the service.
     fn connect(&mut self, client: ClientId) {}
 
+    /// Called in place of [StateroomService::connect] with metadata supplied by the
+    /// client, returning an error if the service rejects the client. A rejected client's
+    /// connection is closed with the error as the reason, and `disconnect` is not called
+    /// for it. By default, it ignores the metadata, calls `connect`, and accepts the client.
+    fn connect_with_metadata(
+        &mut self,
+        client: ClientId,
+        metadata: &ConnectionMetadata,
+    ) -> Result<(), String> {
+        self.connect(client);
+        Ok(())
+    }
+
     /// Called each time a client disconnects from the service, unless that disconnection
     /// will cause the service to be destroyed.
     fn disconnect(&mut self, client: ClientId) {}
 
+    /// Called in place of [StateroomService::disconnect] with the reason for the
+    /// disconnection. By default, it ignores the reason and calls `disconnect`.
+    fn disconnect_with_reason(&mut self, client: ClientId, reason: DisconnectReason) {
+        self.disconnect(client);
+    }
+
     /// Called each time a client sends a text message to the service.
     fn message(&mut self, client: ClientId, message: &str) {}
 
@@ -141,6 +232,28 @@ pub trait StateroomService {
     /// Called when [StateroomContext::set_timer] has been called on this service's context,
     /// after the provided duration.
     fn timer(&mut self) {}
+
+    /// Returns the service's state, in a form that [StateroomService::restore] can read.
+    /// Returns `None` (default) if the service does not support snapshots.
+    ///
+    /// This takes `&mut self` because some services (such as WebAssembly modules) must
+    /// run code to produce a snapshot.
+    fn snapshot(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Replaces the service's state with one returned by [StateroomService::snapshot],
+    /// returning an error if it cannot be restored. By default, snapshots are not
+    /// supported and an error is returned.
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), String> {
+        Err("Service does not support restoring snapshots.".to_string())
+    }
+
+    /// The types of message the service handles. Clients' messages of other types are
+    /// rejected instead of being passed to `message` or `binary`. By default, both are accepted.
+    fn accepted_messages(&self) -> AcceptedMessages {
+        AcceptedMessages::ALL
+    }
 }
 
 /// Enables an object to become a [StateroomService] of the associated `Service` type.
@@ -151,6 +264,13 @@ pub trait StateroomServiceFactory<C: StateroomContext> {
 
     /// Non-destructively build a [StateroomService] from `self`.
     fn build(&self, room_id: &str, context: C) -> Result<Self::Service, Self::Error>;
+
+    /// Reload whatever services are built from (such as a module on disk), so
+    /// that services built afterwards use the new version. If this fails, the
+    /// factory keeps building services as before. Does nothing by default.
+    fn refresh(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
 }
 
 impl<C: StateroomContext, S: SimpleStateroomService + Clone> StateroomServiceFactory<C> for S {
@@ -182,10 +302,26 @@ impl<S: SimpleStateroomService, C: StateroomContext> StateroomService
         self.service.connect(client, &self.context);
     }
 
+    fn connect_with_metadata(
+        &mut self,
+        client: ClientId,
+        metadata: &ConnectionMetadata,
+    ) -> Result<(), String> {
+        self.service.authorize(client, metadata, &self.context)?;
+        self.service
+            .connect_with_metadata(client, metadata, &self.context);
+        Ok(())
+    }
+
     fn disconnect(&mut self, client: ClientId) {
         self.service.disconnect(client, &self.context);
     }
 
+    fn disconnect_with_reason(&mut self, client: ClientId, reason: DisconnectReason) {
+        self.service
+            .disconnect_with_reason(client, reason, &self.context);
+    }
+
     fn message(&mut self, client: ClientId, message: &str) {
         self.service.message(client, message, &self.context);
     }
@@ -197,4 +333,16 @@ impl<S: SimpleStateroomService, C: StateroomContext> StateroomService
     fn binary(&mut self, client: ClientId, message: &[u8]) {
         self.service.binary(client, message, &self.context);
     }
+
+    fn snapshot(&mut self) -> Option<Vec<u8>> {
+        self.service.snapshot()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), String> {
+        self.service.restore(snapshot, &self.context)
+    }
+
+    fn accepted_messages(&self) -> AcceptedMessages {
+        self.service.accepted_messages()
+    }
 }
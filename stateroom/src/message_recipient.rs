@@ -1,12 +1,15 @@
 use crate::ClientId;
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
 
 /// Represents the recipient(s) of a message.
 ///
 /// Messages may either be sent to a particular client by numeric id
-/// (`MessageRecipient::Client(3)`), or be broadcast to all connected clients
-/// (`MessageRecipient::Broadcast`).]
+/// (`MessageRecipient::Client(3)`), to a list of clients
+/// (`MessageRecipient::Clients(vec![3, 4])`), to every connected client except
+/// one (`MessageRecipient::EveryoneExcept(3)`), or be broadcast to all connected
+/// clients (`MessageRecipient::Broadcast`).
 #[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 
@@ -14,15 +17,32 @@ pub enum MessageRecipient {
     Broadcast,
     Client(ClientId),
     EveryoneExcept(ClientId),
+    Clients(Vec<ClientId>),
 }
 
 impl MessageRecipient {
+    /// The `i32` encoding of [MessageRecipient::Clients].
+    ///
+    /// A list of clients does not fit in an `i32`, so it is passed across the
+    /// WebAssembly boundary as a pointer to an array of little-endian `u32`
+    /// client IDs and a count (see [MessageRecipient::decode_clients]). This
+    /// value is reserved so that it never collides with the other encodings;
+    /// decoding it alone yields an empty list.
+    pub const CLIENT_LIST: i32 = i32::MIN;
+
+    /// Encodes the recipient as an `i32`: `0` for a broadcast, a positive client
+    /// ID for a single client, a negated client ID for everyone except that
+    /// client, and [MessageRecipient::CLIENT_LIST] for a list of clients.
+    ///
+    /// Returns `None` if the recipient is a single client (or everyone except
+    /// one) whose ID is too large to be represented, that is, at least 2^31.
     #[must_use]
-    pub fn encode_i32(&self) -> i32 {
+    pub fn encode_i32(&self) -> Option<i32> {
         match self {
-            Self::Broadcast => 0,
-            Self::Client(c) => c.0 as i32,
-            Self::EveryoneExcept(c) => -(c.0 as i32),
+            Self::Broadcast => Some(0),
+            Self::Client(c) => i32::try_from(c.0).ok(),
+            Self::EveryoneExcept(c) => i32::try_from(c.0).ok().map(|c| -c),
+            Self::Clients(_) => Some(Self::CLIENT_LIST),
         }
     }
 
@@ -30,10 +50,22 @@ impl MessageRecipient {
     pub fn decode_i32(enc_client_id: i32) -> Self {
         match enc_client_id {
             0 => Self::Broadcast,
+            Self::CLIENT_LIST => Self::Clients(Vec::new()),
             c if c > 0 => Self::Client((c as u32).into()),
             c => Self::EveryoneExcept((-c as u32).into()),
         }
     }
+
+    /// Decodes a list of client IDs from an array of little-endian `u32`s.
+    /// Trailing bytes that do not make up a whole `u32` are ignored.
+    #[must_use]
+    pub fn decode_clients(data: &[u8]) -> Self {
+        Self::Clients(
+            data.chunks_exact(std::mem::size_of::<u32>())
+                .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]).into())
+                .collect(),
+        )
+    }
 }
 
 impl From<ClientId> for MessageRecipient {
@@ -58,12 +90,66 @@ mod tests {
             ClientId::from(9u32).into()
         );
 
-        assert_eq!(0, MessageRecipient::Broadcast.encode_i32());
-        assert_eq!(443, MessageRecipient::Client(443.into()).encode_i32());
+        assert_eq!(Some(0), MessageRecipient::Broadcast.encode_i32());
+        assert_eq!(Some(443), MessageRecipient::Client(443.into()).encode_i32());
+
+        assert_eq!(
+            Some(-4),
+            MessageRecipient::EveryoneExcept(4.into()).encode_i32()
+        );
+        assert_eq!(
+            MessageRecipient::EveryoneExcept(119.into()),
+            MessageRecipient::decode_i32(-119)
+        );
+
+        assert_eq!(
+            MessageRecipient::EveryoneExcept(1.into()),
+            MessageRecipient::decode_i32(-1)
+        );
+    }
+
+    #[test]
+    fn test_encode_largest_client_id() {
+        let largest = ClientId::from(i32::MAX as u32);
+        for recipient in [
+            MessageRecipient::Client(largest),
+            MessageRecipient::EveryoneExcept(largest),
+        ] {
+            let encoded = recipient.encode_i32().unwrap();
+            assert_eq!(recipient, MessageRecipient::decode_i32(encoded));
+        }
 
-        assert_eq!(-4, MessageRecipient::EveryoneExcept(4.into()).encode_i32());
-        assert_eq!(MessageRecipient::EveryoneExcept(119.into()), MessageRecipient::decode_i32(-119));
+        // Larger IDs would wrap into other recipients (2^31 into CLIENT_LIST),
+        // so they cannot be encoded.
+        for id in [1 << 31, u32::MAX] {
+            assert_eq!(None, MessageRecipient::Client(id.into()).encode_i32());
+            assert_eq!(
+                None,
+                MessageRecipient::EveryoneExcept(id.into()).encode_i32()
+            );
+        }
+    }
+
+    #[test]
+    fn test_client_list() {
+        let clients = MessageRecipient::Clients(vec![3.into(), 8.into()]);
+        assert_eq!(Some(MessageRecipient::CLIENT_LIST), clients.encode_i32());
+        assert_eq!(
+            MessageRecipient::Clients(Vec::new()),
+            MessageRecipient::decode_i32(MessageRecipient::CLIENT_LIST)
+        );
 
-        assert_eq!(MessageRecipient::EveryoneExcept(1.into()), MessageRecipient::decode_i32(-1));
+        assert_eq!(
+            MessageRecipient::Clients(Vec::new()),
+            MessageRecipient::decode_clients(&[])
+        );
+        assert_eq!(
+            MessageRecipient::Clients(vec![7.into()]),
+            MessageRecipient::decode_clients(&[7, 0, 0, 0])
+        );
+        assert_eq!(
+            clients,
+            MessageRecipient::decode_clients(&[3, 0, 0, 0, 8, 0, 0, 0, 1])
+        );
     }
 }
@@ -1,22 +1,45 @@
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
-use crate::{ClientId, MessageRecipient};
+use crate::{ClientId, ConnectionMetadata, DisconnectReason, MessageRecipient};
 
+/// The body of a message passed to or from a process. Bytes are serialized as a
+/// base64 string, so that arbitrary binary data survives line-delimited JSON.
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum MessagePayload {
-    Bytes(Vec<u8>),
+    Bytes(#[cfg_attr(feature = "serde", serde(with = "base64_bytes"))] Vec<u8>),
     Text(String),
 }
 
+#[cfg(feature = "serde")]
+mod base64_bytes {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        base64::decode(encoded).map_err(D::Error::custom)
+    }
+}
+
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[cfg_attr(feature = "serde", serde(tag = "type"))]
 pub enum MessageToProcess {
     Connect {
         client: ClientId,
+        #[cfg_attr(
+            feature = "serde",
+            serde(default, skip_serializing_if = "ConnectionMetadata::is_empty")
+        )]
+        metadata: ConnectionMetadata,
     },
     Disconnect {
         client: ClientId,
+        #[cfg_attr(feature = "serde", serde(default))]
+        reason: DisconnectReason,
     },
     Message {
         client: ClientId,
@@ -32,4 +55,18 @@ pub enum MessageFromProcess {
         recipient: MessageRecipient,
         message: MessagePayload,
     },
+    /// Requests a call to `timer` after the given number of milliseconds.
+    /// See [crate::StateroomContext::set_timer].
+    SetTimer { duration_ms: u32 },
+    /// Declares which optional features the process supports. Sent by the
+    /// process as its first message; a process that never sends it is assumed
+    /// to support everything.
+    Capabilities {
+        /// Whether the process handles `Timer` messages.
+        #[cfg_attr(feature = "serde", serde(default))]
+        timer: bool,
+        /// Whether the process handles messages with a `Bytes` payload.
+        #[cfg_attr(feature = "serde", serde(default))]
+        binary: bool,
+    },
 }